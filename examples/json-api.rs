@@ -7,15 +7,32 @@ extern crate http;
 use http::status;
 use http::status::{NotFound, NoContent};
 use nickel::{Nickel, Request, Response, HttpRouter, JsonBody, Continue,
-    MiddlewareResult, QueryString
+    Middleware, MiddlewareResult, QueryString
 };
 use nickel::mimes;
 use std::io::net::ip::Ipv4Addr;
 use serialize::json;
 use orchestrate::Orchestrate;
+use orchestrate::credentials::EnvCredentials;
 use std::error::Error;
 
-const ORC_API_KEY: &'static str = env!("ORC_API_KEY");
+// Hands every request a clone of the one client built in main, so they all
+// share its connection pool.
+#[deriving(Clone)]
+struct Client {
+    client: Orchestrate
+}
+
+impl Middleware for Client {
+    fn invoke(&self, req: &mut Request, _res: &mut Response) -> MiddlewareResult {
+        req.map.insert(self.client.clone());
+        Ok(Continue)
+    }
+}
+
+fn orchestrate(req: &Request) -> &Orchestrate {
+    req.map.get::<Orchestrate>().unwrap()
+}
 
 #[deriving(Encodable, Decodable)]
 struct User {
@@ -32,6 +49,11 @@ struct Update {
 fn main() {
     let mut server = Nickel::new();
 
+    // the key is read from ORC_API_KEY at runtime, so it can be rotated
+    // without rebuilding or restarting
+    let mut client = Orchestrate::new("");
+    client.set_credentials(EnvCredentials::new("ORC_API_KEY"));
+
     fn logger(request: &Request, _response: &mut Response) -> MiddlewareResult {
         println!("logging request: {}", request.origin.request_uri);
 
@@ -39,6 +61,7 @@ fn main() {
     }
 
     server.utilize(logger);
+    server.utilize(Client { client: client });
     server.utilize(Nickel::json_body_parser());
     server.utilize(Nickel::query_string());
 
@@ -55,7 +78,7 @@ fn main() {
 }
 
 fn get_user (req: &Request, res: &mut Response) -> (status::Status, String) {
-    let mut client = Orchestrate::new(ORC_API_KEY);
+    let client = orchestrate(req);
     res.content_type(mimes::Json);
 
     match client.get("users", req.param("user_key")).exec::<User>() {
@@ -65,7 +88,7 @@ fn get_user (req: &Request, res: &mut Response) -> (status::Status, String) {
 }
 
 fn get_users (req: &Request, res: &mut Response) -> (status::Status, String) {
-    let mut client = Orchestrate::new(ORC_API_KEY);
+    let client = orchestrate(req);
     res.content_type(mimes::Json);
 
     match client.list("users").limit(100).exec::<User>() {
@@ -75,7 +98,7 @@ fn get_users (req: &Request, res: &mut Response) -> (status::Status, String) {
 }
 
 fn post_user (req: &Request, res: &mut Response) -> (status::Status, String) {
-    let mut client = Orchestrate::new(ORC_API_KEY);
+    let client = orchestrate(req);
     let user = req.json_as::<User>().unwrap();
     res.content_type(mimes::Json);
 
@@ -86,7 +109,7 @@ fn post_user (req: &Request, res: &mut Response) -> (status::Status, String) {
 }
 
 fn put_user (req: &Request, res: &mut Response) -> (status::Status, String) {
-    let mut client = Orchestrate::new(ORC_API_KEY);
+    let client = orchestrate(req);
     let user = req.json_as::<User>().unwrap();
     res.content_type(mimes::Json);
 
@@ -97,7 +120,7 @@ fn put_user (req: &Request, res: &mut Response) -> (status::Status, String) {
 }
 
 fn delete_user (req: &Request, res: &mut Response) -> (status::Status, String) {
-    let mut client = Orchestrate::new(ORC_API_KEY);
+    let client = orchestrate(req);
     res.content_type(mimes::Json);
 
     match client.delete("users", req.param("user_key")).purge().exec() {
@@ -107,7 +130,7 @@ fn delete_user (req: &Request, res: &mut Response) -> (status::Status, String) {
 }
 
 fn search_users (req: &Request, res: &mut Response) -> (status::Status, String) {
-    let mut client = Orchestrate::new(ORC_API_KEY);
+    let client = orchestrate(req);
     res.content_type(mimes::Json);
 
     match client.search("users")
@@ -121,7 +144,7 @@ fn search_users (req: &Request, res: &mut Response) -> (status::Status, String)
 }
 
 fn get_updates (req: &Request, res: &mut Response) -> (status::Status, String) {
-    let mut client = Orchestrate::new(ORC_API_KEY);
+    let client = orchestrate(req);
     res.content_type(mimes::Json);
 
     match client.get_events("users", req.param("user_key"), "update")
@@ -133,7 +156,7 @@ fn get_updates (req: &Request, res: &mut Response) -> (status::Status, String) {
 }
 
 fn post_update (req: &Request, res: &mut Response) -> (status::Status, String) {
-    let mut client = Orchestrate::new(ORC_API_KEY);
+    let client = orchestrate(req);
     let update = req.json_as::<Update>().unwrap();
     res.content_type(mimes::Json);
 
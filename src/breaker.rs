@@ -0,0 +1,260 @@
+use error::OrchestrateError;
+use error::OrchestrateError::Validation;
+use std::collections::RingBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time;
+
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen
+}
+
+pub trait CircuitListener {
+    fn transition(&self, from: CircuitState, to: CircuitState);
+}
+
+// What the client may do with a request it is about to send.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Admission {
+    Allow,
+    // the breaker has been open long enough, check the server before sending
+    Probe,
+    Reject
+}
+
+struct BreakerState {
+    state: CircuitState,
+    outcomes: RingBuf<bool>,
+    opened: u64
+}
+
+// Opens once the ratio of failed attempts over the last `window` attempts
+// reaches `failure_ratio`, rejecting every request until `open_for` has passed
+// and a ping to the server succeeds.
+#[deriving(Clone)]
+pub struct CircuitBreaker {
+    failure_ratio: f64,
+    window: uint,
+    min_requests: uint,
+    open_for: Duration,
+    listener: Option<Arc<Box<CircuitListener + Send + Sync>>>,
+    state: Arc<Mutex<BreakerState>>
+}
+
+impl CircuitBreaker {
+
+    pub fn new() -> CircuitBreaker {
+        CircuitBreaker {
+            failure_ratio: 0.5,
+            window: 20,
+            min_requests: 10,
+            open_for: Duration::seconds(30),
+            listener: None,
+            state: Arc::new(Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                outcomes: RingBuf::new(),
+                opened: 0
+            }))
+        }
+    }
+
+    // between 0, exclusive, and 1
+    pub fn failure_ratio(mut self, ratio: f64)
+                         -> Result<CircuitBreaker, OrchestrateError> {
+        if !(ratio > 0.0 && ratio <= 1.0) {
+            return Err(Validation(format!(
+                "invalid failure ratio {}, must be above 0 and at most 1", ratio)));
+        }
+        self.failure_ratio = ratio;
+        Ok(self)
+    }
+
+    // how many of the latest attempts the ratio is computed over, at least
+    // `min_requests` or the breaker could never open
+    pub fn window(mut self, window: uint)
+                  -> Result<CircuitBreaker, OrchestrateError> {
+        if window == 0 || window < self.min_requests {
+            return Err(Validation(format!(
+                "invalid window {}, must be at least min_requests ({})",
+                window, self.min_requests)));
+        }
+        self.window = window;
+        Ok(self)
+    }
+
+    // the breaker never opens on fewer attempts than this; it can't exceed
+    // the window, so set that first when raising both and last when lowering
+    pub fn min_requests(mut self, min_requests: uint)
+                        -> Result<CircuitBreaker, OrchestrateError> {
+        if min_requests == 0 || min_requests > self.window {
+            return Err(Validation(format!(
+                "invalid min_requests {}, must be between 1 and the window ({})",
+                min_requests, self.window)));
+        }
+        self.min_requests = min_requests;
+        Ok(self)
+    }
+
+    pub fn open_for(mut self, open_for: Duration) -> CircuitBreaker {
+        self.open_for = open_for;
+        self
+    }
+
+    pub fn listener<L: CircuitListener + Send + Sync>(mut self, listener: L)
+                                                       -> CircuitBreaker {
+        self.listener = Some(Arc::new(box listener as Box<CircuitListener + Send + Sync>));
+        self
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state.lock().state.clone()
+    }
+
+    // only one caller gets to probe, everyone else is rejected until the
+    // probe has been reported through `probed`
+    pub fn admit(&self) -> Admission {
+        {
+            let mut state = self.state.lock();
+            match state.state {
+                CircuitState::Closed => return Admission::Allow,
+                CircuitState::HalfOpen => return Admission::Reject,
+                CircuitState::Open => {
+                    let open = Duration::nanoseconds(
+                        (time::precise_time_ns() - state.opened) as i64);
+                    if open < self.open_for {
+                        return Admission::Reject;
+                    }
+                    state.state = CircuitState::HalfOpen;
+                }
+            }
+        }
+
+        self.notify(CircuitState::Open, CircuitState::HalfOpen);
+        Admission::Probe
+    }
+
+    // reports the outcome of an attempt let through while closed
+    pub fn record(&self, success: bool) {
+        {
+            let mut state = self.state.lock();
+            if state.state != CircuitState::Closed {
+                return;
+            }
+
+            state.outcomes.push_back(success);
+            while state.outcomes.len() > self.window {
+                state.outcomes.pop_front();
+            }
+
+            let total = state.outcomes.len();
+            let failures = state.outcomes.iter().filter(|ok| !**ok).count();
+            if total < self.min_requests ||
+               (failures as f64) < self.failure_ratio * total as f64 {
+                return;
+            }
+
+            state.state = CircuitState::Open;
+            state.opened = time::precise_time_ns();
+            state.outcomes.clear();
+        }
+
+        self.notify(CircuitState::Closed, CircuitState::Open);
+    }
+
+    // reports the outcome of the probe handed out by `admit`
+    pub fn probed(&self, success: bool) {
+        let to = {
+            let mut state = self.state.lock();
+            if state.state != CircuitState::HalfOpen {
+                return;
+            }
+
+            if success {
+                state.state = CircuitState::Closed;
+            } else {
+                state.state = CircuitState::Open;
+                state.opened = time::precise_time_ns();
+            }
+            state.state.clone()
+        };
+
+        self.notify(CircuitState::HalfOpen, to);
+    }
+
+    fn notify(&self, from: CircuitState, to: CircuitState) {
+        match self.listener {
+            Some(ref listener) => listener.transition(from, to),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, Admission};
+    use super::CircuitState::{Closed, Open, HalfOpen};
+    use std::time::Duration;
+
+    fn breaker(open_for: Duration) -> CircuitBreaker {
+        CircuitBreaker::new().min_requests(4).unwrap()
+                             .window(4).unwrap()
+                             .failure_ratio(0.5).unwrap()
+                             .open_for(open_for)
+    }
+
+    #[test]
+    fn rejects_settings_that_never_or_always_open() {
+        assert!(CircuitBreaker::new().failure_ratio(0.0).is_err());
+        assert!(CircuitBreaker::new().failure_ratio(1.5).is_err());
+        assert!(CircuitBreaker::new().window(0).is_err());
+        assert!(CircuitBreaker::new().window(5).is_err());
+        assert!(CircuitBreaker::new().min_requests(0).is_err());
+        assert!(CircuitBreaker::new().min_requests(21).is_err());
+    }
+
+    #[test]
+    fn opens_once_enough_attempts_fail() {
+        let breaker = breaker(Duration::seconds(30));
+        breaker.record(true);
+        breaker.record(false);
+        breaker.record(true);
+        assert_eq!(breaker.state(), Closed);
+
+        breaker.record(false);
+        assert_eq!(breaker.state(), Open);
+        assert_eq!(breaker.admit(), Admission::Reject);
+    }
+
+    #[test]
+    fn stays_closed_on_healthy_traffic() {
+        let breaker = breaker(Duration::seconds(30));
+        for _ in range(0u, 10) {
+            breaker.record(true);
+        }
+        assert_eq!(breaker.state(), Closed);
+        assert_eq!(breaker.admit(), Admission::Allow);
+    }
+
+    #[test]
+    fn probes_once_open_long_enough() {
+        let breaker = breaker(Duration::zero());
+        for _ in range(0u, 4) {
+            breaker.record(false);
+        }
+
+        assert_eq!(breaker.admit(), Admission::Probe);
+        assert_eq!(breaker.state(), HalfOpen);
+        // only one caller gets to probe
+        assert_eq!(breaker.admit(), Admission::Reject);
+
+        breaker.probed(false);
+        assert_eq!(breaker.state(), Open);
+
+        assert_eq!(breaker.admit(), Admission::Probe);
+        breaker.probed(true);
+        assert_eq!(breaker.state(), Closed);
+    }
+}
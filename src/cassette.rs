@@ -0,0 +1,263 @@
+use transport::{Transport, Request, Response};
+use error::OrchestrateError;
+use error::OrchestrateError::{Protocol, NoResponse, Validation};
+use hyper::header::Headers;
+use hyper::status::StatusCode;
+use serialize::json;
+use std::ascii::AsciiExt;
+use std::io::{File, MemReader};
+use std::num::FromPrimitive;
+use std::sync::{Arc, Mutex};
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum CassetteMode {
+    Record,
+    Replay
+}
+
+#[deriving(Clone, Encodable, Decodable, Show)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>
+}
+
+#[deriving(Clone, Encodable, Decodable, Show)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String
+}
+
+#[deriving(Clone, Encodable, Decodable, Show)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse
+}
+
+impl RecordedRequest {
+
+    // credentials never make it into a cassette
+    fn from_request(req: &Request) -> RecordedRequest {
+        RecordedRequest {
+            method: req.method.to_string(),
+            path: req.url.serialize_path().unwrap_or(String::new()),
+            query: req.url.query.clone(),
+            headers: req.headers.iter()
+                                .filter(|&&(ref name, _)| {
+                                    !name.as_slice()
+                                         .eq_ignore_ascii_case("Authorization")
+                                })
+                                .map(|header| header.clone())
+                                .collect(),
+            body: req.body.clone()
+        }
+    }
+
+    // headers are left out on purpose, they carry the user agent version
+    fn matches(&self, other: &RecordedRequest) -> bool {
+        self.method == other.method && self.path == other.path &&
+            self.query == other.query && self.body == other.body
+    }
+}
+
+impl RecordedResponse {
+    fn to_response(&self) -> Result<Response, OrchestrateError> {
+        let status: StatusCode = match FromPrimitive::from_u16(self.status) {
+            Some(status) => status,
+            None => return Err(Protocol(
+                format!("invalid status code {} in cassette", self.status)))
+        };
+
+        let mut headers = Headers::new();
+        for &(ref name, ref value) in self.headers.iter() {
+            headers.set_raw(name.to_string(), vec![value.as_bytes().to_vec()]);
+        }
+
+        Ok(Response::new(status, headers,
+                         box MemReader::new(self.body.clone().into_bytes())))
+    }
+}
+
+struct CassetteState {
+    interactions: Vec<Interaction>,
+    used: Vec<bool>
+}
+
+// Records every interaction with the wrapped transport into a JSON file, or
+// replays a recorded file without touching the network. Clones share the same
+// file and interactions.
+#[deriving(Clone)]
+pub struct Cassette {
+    path: Path,
+    mode: CassetteMode,
+    inner: Option<Arc<Box<Transport + Send + Sync>>>,
+    state: Arc<Mutex<CassetteState>>
+}
+
+impl Cassette {
+
+    pub fn record(path: Path, inner: Arc<Box<Transport + Send + Sync>>)
+                  -> Cassette {
+        Cassette {
+            path: path,
+            mode: CassetteMode::Record,
+            inner: Some(inner),
+            state: Arc::new(Mutex::new(CassetteState {
+                interactions: Vec::new(),
+                used: Vec::new()
+            }))
+        }
+    }
+
+    pub fn replay(path: Path) -> Result<Cassette, OrchestrateError> {
+        let contents = match File::open(&path).read_to_string() {
+            Ok(contents) => contents,
+            Err(err) => return Err(Validation(format!(
+                "can't read cassette {}: {}", path.display(), err)))
+        };
+        let interactions: Vec<Interaction> = try!(json::decode(contents.as_slice()));
+        let used = Vec::from_elem(interactions.len(), false);
+
+        Ok(Cassette {
+            path: path,
+            mode: CassetteMode::Replay,
+            inner: None,
+            state: Arc::new(Mutex::new(CassetteState {
+                interactions: interactions,
+                used: used
+            }))
+        })
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode.clone()
+    }
+
+    // the same cassette recording through another transport; a replaying
+    // cassette never sends anything, so it is left as it is
+    pub fn wrap(&self, inner: Arc<Box<Transport + Send + Sync>>) -> Cassette {
+        let mut cassette = self.clone();
+        if cassette.mode == CassetteMode::Record {
+            cassette.inner = Some(inner);
+        }
+        cassette
+    }
+
+    fn record_send(&self, inner: &Arc<Box<Transport + Send + Sync>>,
+                   req: Request) -> Result<Response, OrchestrateError> {
+        let recorded = RecordedRequest::from_request(&req);
+        let mut res = try!(inner.send(req));
+        let body = try!(res.read_to_end());
+
+        let interaction = Interaction {
+            request: recorded,
+            response: RecordedResponse {
+                status: res.status as u16,
+                headers: res.headers.iter()
+                                    .map(|header| (header.name().to_string(),
+                                                   header.value_string()))
+                                    .collect(),
+                body: String::from_utf8_lossy(body.as_slice()).into_string()
+            }
+        };
+
+        // rewrite the whole file each time so an aborted run still leaves a
+        // usable cassette behind
+        let mut state = self.state.lock();
+        state.interactions.push(interaction);
+        let mut file = try!(File::create(&self.path));
+        try!(file.write_str(json::encode(&state.interactions).as_slice()));
+
+        Ok(Response::new(res.status, res.headers.clone(),
+                         box MemReader::new(body)))
+    }
+
+    fn replay_send(&self, req: Request) -> Result<Response, OrchestrateError> {
+        let wanted = RecordedRequest::from_request(&req);
+        let mut state = self.state.lock();
+
+        let found = range(0, state.interactions.len()).find(|&i| {
+            !state.used[i] && state.interactions[i].request.matches(&wanted)
+        });
+
+        match found {
+            Some(i) => {
+                state.used[i] = true;
+                state.interactions[i].response.to_response()
+            },
+            None => Err(NoResponse(format!(
+                "no unused interaction in cassette {} matches {} {}{}",
+                self.path.display(), wanted.method, wanted.path,
+                wanted.query.map_or(String::new(), |q| format!("?{}", q)))))
+        }
+    }
+}
+
+impl Transport for Cassette {
+    fn send(&self, req: Request) -> Result<Response, OrchestrateError> {
+        match self.inner {
+            Some(ref inner) => self.record_send(inner, req),
+            None => self.replay_send(req)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CassetteMode::{Record, Replay};
+    use Orchestrate;
+    use error::ErrorKind;
+    use transport::MemoryTransport;
+    use std::io::fs;
+    use std::os;
+
+    #[deriving(Decodable, Show)]
+    struct User {
+        name: String
+    }
+
+    #[test]
+    fn replays_what_was_recorded() {
+        let path = os::tmpdir().join("orchestrate-cassette-test.json");
+
+        let transport = MemoryTransport::new();
+        transport.respond(200, "{\"name\": \"bob\"}");
+        let mut client = Orchestrate::new("key");
+        client.set_transport(transport);
+        client.set_cassette(path.clone(), Record).unwrap();
+        client.get("users", "bob").exec::<User>().unwrap();
+
+        let mut client = Orchestrate::new("key");
+        client.set_cassette(path.clone(), Replay).unwrap();
+        // reconfiguring HTTP must not bypass the cassette
+        client.set_proxy(None);
+
+        let user = client.get("users", "bob").exec::<User>().unwrap();
+        assert_eq!(user.value.name.as_slice(), "bob");
+
+        // each interaction is only replayed once
+        let err = client.get("users", "bob").exec::<User>().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NoResponse);
+
+        fs::unlink(&path).unwrap();
+    }
+
+    #[test]
+    fn never_records_the_api_key() {
+        let path = os::tmpdir().join("orchestrate-cassette-key-test.json");
+
+        let transport = MemoryTransport::new();
+        transport.respond(200, "{\"name\": \"bob\"}");
+        let mut client = Orchestrate::new("secret-key");
+        client.set_transport(transport);
+        client.set_cassette(path.clone(), Record).unwrap();
+        client.get("users", "bob").exec::<User>().unwrap();
+
+        let recorded = fs::File::open(&path).read_to_string().unwrap();
+        assert!(!recorded.as_slice().contains("Authorization"));
+        fs::unlink(&path).unwrap();
+    }
+}
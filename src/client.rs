@@ -1,106 +1,769 @@
-use hyper::client;
-use hyper::client::Response;
-use hyper::method::Method;
-use hyper::header::common::{
-    UserAgent, ContentType, ContentLength, Authorization
-};
-use hyper::header::common::authorization::Basic;
-use error::OrchestrateError;
+use hyper::method::{Method, Get, Head, Put, Delete};
+use error::{OrchestrateError, ApiError};
+use transport::{Transport, HttpTransport, Request, Response, Timeouts, compress};
+use retry::RetryPolicy;
+use operation::Operation;
+use operation::Operation::{Ping, KvCreate, KvUpdate, EventsCreate};
+use metrics::{MetricsSink, Metric};
+use rate_limit::RateLimiter;
+use breaker::{CircuitBreaker, Admission};
+use hosts::Hosts;
+use cassette::Cassette;
+use credentials::{CredentialProvider, StaticCredentials};
+use dry_run::RenderedRequest;
+use middleware::Middleware;
+use names;
+use names::Segment;
+use observer::{Observer, Exchange, REDACTED};
+use serialize::base64::{ToBase64, STANDARD};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Future, TaskPool};
+use std::sync::atomic::{AtomicBool, SeqCst};
+use std::io::{timer, MemReader, IoResult};
+use std::ascii::AsciiExt;
+use std::fmt;
+use std::cmp;
+use std::time::Duration;
+use time;
 use hyper::Url;
 use url::form_urlencoded::serialize_owned;
 
-#[deriving(Clone, Show)]
+#[deriving(Clone)]
 pub struct Client {
-    pub host: String,
-    token: String,
+    pub scheme: String,
+    pub hosts: Hosts,
+    pub port: Option<u16>,
+    // path segments before every request path, the API version included
+    pub prefix: String,
+    pub health_check: Option<Arc<HealthCheck>>,
+    pub transport: Arc<Box<Transport + Send + Sync>>,
+    pub http: HttpTransport,
+    // kept apart from `transport` so reconfiguring HTTP doesn't lose it
+    pub cassette: Option<Cassette>,
+    pub retry: RetryPolicy,
+    pub timeouts: Timeouts,
+    pub observer: Option<Arc<Box<Observer + Send + Sync>>>,
+    pub metrics: Option<Arc<Box<MetricsSink + Send + Sync>>>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub breaker: Option<CircuitBreaker>,
+    pub credentials: Arc<Box<CredentialProvider + Send + Sync>>,
+    pub dry_run: bool,
+    pub gzip_threshold: Option<uint>,
+    pub middleware: Vec<Arc<Box<Middleware + Send + Sync>>>,
+    pub tasks: Tasks,
     user_agent: String,
-    url: Option<Url>,
-    method: Option<Method>,
-    content_type: String,
-    headers: HashMap<String, String>,
-    query: Vec<(String, String)>,
-    body: Option<String>
+    content_type: String
 }
 
-impl Client {
+#[deriving(Clone, Show)]
+pub struct RequestBuilder {
+    pub operation: Operation,
+    pub collection: String,
+    // relative to the base url, collection included
+    pub path: String,
+    pub method: Method,
+    pub headers: HashMap<String, String>,
+    pub query: Vec<(String, String)>,
+    pub body: Option<String>,
+    pub retryable: Option<bool>,
+    pub timeout: Option<Duration>,
+    // why the request can't be sent, if it can't
+    pub invalid: Option<String>
+}
 
-    pub fn new(token: &str) -> Client {
-        Client {
-            host: "api.orchestrate.io".to_string(),
-            token: token.to_string(),
-            user_agent: version(),
-            url: None,
-            method: None,
-            content_type: "application/json".to_string(),
+impl RequestBuilder {
+
+    pub fn new(operation: Operation, collection: &str, path: &str)
+               -> RequestBuilder {
+        RequestBuilder {
+            method: operation.method(),
+            operation: operation,
+            collection: collection.to_string(),
+            path: path.to_string(),
             headers: HashMap::new(),
             query: Vec::new(),
-            body: None
+            body: None,
+            retryable: None,
+            timeout: None,
+            invalid: None
+        }
+    }
+
+    // an invalid name in `segments` fails the request once it is executed
+    pub fn with_path(operation: Operation, collection: &str,
+                     segments: &[Segment]) -> RequestBuilder {
+        let mut request = RequestBuilder::new(operation, collection, "");
+        request.segments(segments);
+        request
+    }
+
+    pub fn segments(&mut self, segments: &[Segment]) -> &mut RequestBuilder {
+        match names::path(segments) {
+            Ok(path) => self.path = path,
+            Err(err) => self.invalid = Some(err)
+        }
+        self
+    }
+
+    // moves the request to another collection, path included
+    pub fn rename_collection(&mut self, collection: &str) -> &mut RequestBuilder {
+        match names::validate(&Segment::Collection(collection)) {
+            Ok(()) => {},
+            Err(err) => self.invalid = Some(err)
         }
+
+        let rest = {
+            let path = self.path.as_slice();
+            let old = names::encode(self.collection.as_slice());
+            if path.starts_with(old.as_slice()) {
+                Some(path.slice_from(old.len()).to_string())
+            } else {
+                None
+            }
+        };
+
+        match rest {
+            Some(ref rest) if rest.is_empty() || rest.as_slice().starts_with("/") ||
+                              rest.as_slice().starts_with("?") => {
+                self.path = format!("{}{}", names::encode(collection), rest);
+            },
+            _ => {}
+        }
+        self.collection = collection.to_string();
+        self
     }
 
-    pub fn trailing(&mut self, url: &str) -> &mut Client {
-        self.url = Some(Url::parse(format!("https://{}/v0/{}",
-                                           self.host.as_slice(),
-                                           url.as_slice()).as_slice()).unwrap());
+    pub fn trailing(&mut self, path: &str) -> &mut RequestBuilder {
+        self.path = path.to_string();
         self
     }
 
-    pub fn header(&mut self, name: &str, value: &str) -> &mut Client {
+    pub fn header(&mut self, name: &str, value: &str) -> &mut RequestBuilder {
         self.headers.insert(name.to_string(), value.to_string());
         self
     }
 
-    pub fn query(&mut self, name: &str, value: &str) -> &mut Client {
+    pub fn query(&mut self, name: &str, value: &str) -> &mut RequestBuilder {
         self.query.push((name.to_string(), value.to_string()));
         self
     }
 
-    pub fn body(&mut self, body: &str) -> &mut Client {
+    pub fn body(&mut self, body: &str) -> &mut RequestBuilder {
         self.body = Some(body.to_string());
         self
     }
 
-    pub fn method(&mut self, method: Method) -> &mut Client {
-        self.method = Some(method);
+    pub fn timeout(&mut self, timeout: Duration) -> &mut RequestBuilder {
+        self.timeout = Some(timeout);
         self
     }
 
-    pub fn exec(&self) -> Result<Response, OrchestrateError> {
-        let mut url = self.url.clone().unwrap();
+    pub fn retryable(&mut self, retryable: bool) -> &mut RequestBuilder {
+        self.retryable = Some(retryable);
+        self
+    }
 
-        if !self.query.is_empty() {
-          url.query = Some(serialize_owned(self.query.as_slice()));
+    // GET, HEAD and DELETE can always be repeated safely, a PUT only when it
+    // is guarded by If-Match; anything else has to opt in explicitly
+    pub fn is_retryable(&self) -> bool {
+        match self.retryable {
+            Some(retryable) => retryable,
+            None => match self.method {
+                Get | Head | Delete => true,
+                Put => self.headers.contains_key(&"If-Match".to_string()),
+                _ => false
+            }
         }
-        println!("{}", url);
-        let mut req = try!(client::Request::new(self.method.clone().unwrap(), url));
+    }
+}
 
-        {
-            let mut headers = req.headers_mut();
-            headers.set(UserAgent(self.user_agent.to_string()));
-            headers.set(Authorization(Basic {
-                username: self.token.to_string(),
-                password: None
-            }));
+impl Client {
 
-            for (name, value) in self.headers.iter() {
-                headers.set_raw(name.to_string(), vec![value.as_bytes().to_vec()]);
+    pub fn new(token: &str) -> Client {
+        let http = HttpTransport::new();
+        Client {
+            scheme: "https".to_string(),
+            hosts: Hosts::new(vec!["api.orchestrate.io".to_string()]),
+            port: None,
+            prefix: "v0".to_string(),
+            health_check: None,
+            transport: Arc::new(box http.clone() as Box<Transport + Send + Sync>),
+            http: http,
+            cassette: None,
+            retry: RetryPolicy::none(),
+            timeouts: Timeouts::none(),
+            observer: None,
+            metrics: None,
+            rate_limiter: None,
+            breaker: None,
+            credentials: Arc::new(box StaticCredentials::new(token)
+                                  as Box<CredentialProvider + Send + Sync>),
+            dry_run: false,
+            gzip_threshold: None,
+            middleware: Vec::new(),
+            tasks: Tasks::new(4),
+            user_agent: version(),
+            content_type: "application/json".to_string()
+        }
+    }
+
+    // anything but a 2xx comes back as `OrchestrateError::ApiError`, the
+    // middleware still sees the response as it was
+    pub fn exec(&self, req: &RequestBuilder)
+                -> Result<Response, OrchestrateError> {
+        self.exec_in(req).map(|(_, res)| res)
+    }
+
+    // like `exec`, also telling which collection the request went to once
+    // the middleware was done with it
+    pub fn exec_in(&self, req: &RequestBuilder)
+                   -> Result<(String, Response), OrchestrateError> {
+        let mut req = req.clone();
+        match self.chain(&mut req) {
+            Ok(res) => {
+                if (res.status as u16) / 100 == 2 {
+                    Ok((req.collection, res))
+                } else {
+                    Err(api_error(&req, res))
+                }
+            },
+            Err(err) => Err(err)
+        }
+    }
+
+    fn chain(&self, req: &mut RequestBuilder)
+             -> Result<Response, OrchestrateError> {
+        if self.middleware.is_empty() {
+            return self.dispatch(req);
+        }
+
+        let mut ran = 0;
+        let mut result = None;
+        for middleware in self.middleware.iter() {
+            ran += 1;
+            result = middleware.request(req).map(|res| Ok(res));
+            if result.is_some() {
+                break;
             }
+        }
+
+        // a short-circuited response is only seen by the middleware before
+        // the one that produced it
+        let mut result = match result {
+            Some(result) => {
+                ran -= 1;
+                result
+            },
+            None => self.dispatch(req)
+        };
 
-            match self.body.clone() {
-                Some(body) => {
-                    headers.set(ContentLength(body.len()));
-                    headers.set(ContentType(from_str("application/json").unwrap()));
+        for middleware in self.middleware.slice_to(ran).iter().rev() {
+            result = middleware.response(req, result);
+        }
+        result
+    }
+
+    fn dispatch(&self, req: &RequestBuilder)
+                -> Result<Response, OrchestrateError> {
+        match req.invalid {
+            Some(ref err) => return Err(OrchestrateError::Validation(err.clone())),
+            None => {}
+        }
+
+        if self.dry_run {
+            return Err(OrchestrateError::DryRun(try!(self.render(req))));
+        }
+
+        let metrics = match self.metrics {
+            Some(ref metrics) => metrics,
+            None => return self.attempt(req)
+        };
+
+        let started = time::precise_time_ns();
+        let result = self.attempt(req);
+
+        let metric = Metric {
+            operation: req.operation.clone(),
+            collection: req.collection.clone(),
+            status: match result {
+                Ok(ref res) => Some(res.status),
+                Err(_) => None
+            },
+            latency: elapsed(started),
+            request_bytes: req.body.as_ref().map_or(0, |body| body.len()),
+            response_bytes: 0
+        };
+
+        // neither a compressed nor a chunked body has a length up front, so
+        // the bytes are counted as they are read and recorded once the body
+        // is dropped
+        match result {
+            Ok(res) => {
+                let status = res.status;
+                let headers = res.headers.clone();
+                Ok(Response::new(status, headers, box Metered {
+                    body: res,
+                    metric: metric,
+                    metrics: metrics.clone()
+                }))
+            },
+            Err(err) => {
+                metrics.record(&metric);
+                Err(err)
+            }
+        }
+    }
+
+    fn attempt(&self, req: &RequestBuilder)
+               -> Result<Response, OrchestrateError> {
+        let total = req.timeout.or(self.timeouts.total);
+        let attempts = if req.is_retryable() { self.retry.attempts() } else { 1 };
+        let started = time::precise_time_ns();
+        let mut attempt = 1;
+
+        loop {
+            // picked again on every attempt so retries fail over
+            let host = self.hosts.active();
+            // asked again on every attempt so a rotated key is picked up
+            let token = try!(self.credentials.token(req.collection.as_slice()));
+            let mut request = try!(self.build(host.as_slice(), token.as_slice(),
+                                              req));
+
+            // the overall deadline spans every attempt, so each one may only
+            // use what is left of it
+            match total {
+                Some(total) => {
+                    let remaining = total - elapsed(started);
+                    if remaining <= Duration::zero() {
+                        return Err(OrchestrateError::Timeout);
+                    }
+                    request.timeouts.connect = Some(shortest(request.timeouts.connect,
+                                                             remaining));
+                    request.timeouts.read = Some(shortest(request.timeouts.read,
+                                                          remaining));
+                },
+                None => {}
+            }
+
+            match self.breaker {
+                Some(ref breaker) => try!(self.admit(breaker)),
+                None => {}
+            }
+
+            // retries spend from the same budget as first attempts
+            match self.rate_limiter {
+                Some(ref limiter) => {
+                    let budget = total.map(|total| total - elapsed(started));
+                    try!(limiter.acquire(&req.operation, budget))
                 },
-                None => headers.set(ContentLength(0))
+                None => {}
             }
+
+            let result = self.send(request, token.as_slice(), attempt);
+            // only a running health check would ever mark the host up again;
+            // a host that stopped answering shows up as a timeout
+            match result {
+                Err(OrchestrateError::IoError(_)) | Err(OrchestrateError::Timeout) |
+                Err(OrchestrateError::HttpError(::hyper::HttpError::HttpIoError(_)))
+                        if self.health_check.is_some() => {
+                    self.hosts.mark(host.as_slice(), false)
+                },
+                _ => {}
+            }
+            match self.breaker {
+                Some(ref breaker) => breaker.record(healthy(&result)),
+                None => {}
+            }
+
+            let retry = match result {
+                Ok(ref res) if attempt < attempts &&
+                               self.retry.should_retry_status(res) => {
+                    Some(self.retry.delay(attempt, Some(res)))
+                },
+                Err(ref err) if attempt < attempts &&
+                                self.retry.should_retry_error(err) => {
+                    Some(self.retry.delay(attempt, None))
+                },
+                _ => None
+            };
+
+            let delay = match retry {
+                Some(delay) => delay,
+                None => return result
+            };
+
+            // don't sleep past the deadline only to give up afterwards
+            match total {
+                Some(total) if delay >= total - elapsed(started) => return result,
+                _ => {}
+            }
+
+            timer::sleep(delay);
+            attempt += 1;
         }
+    }
+
+    // lets a request through the breaker, pinging the server first when the
+    // breaker has been open long enough to try closing again
+    fn admit(&self, breaker: &CircuitBreaker) -> Result<(), OrchestrateError> {
+        match breaker.admit() {
+            Admission::Allow => Ok(()),
+            Admission::Reject => Err(OrchestrateError::CircuitOpen),
+            Admission::Probe => {
+                let up = self.check(self.hosts.active().as_slice());
+                breaker.probed(up);
+                if up { Ok(()) } else { Err(OrchestrateError::CircuitOpen) }
+            }
+        }
+    }
 
-        let mut stream = try!(req.start());
-        if self.body.is_some() {
-            try!(stream.write(self.body.clone().unwrap().as_bytes()));
+    fn check(&self, host: &str) -> bool {
+        let token = match self.credentials.token("") {
+            Ok(token) => token,
+            Err(_) => return false
+        };
+        let ping = RequestBuilder::new(Ping, "", "");
+        match self.build(host, token.as_slice(), &ping) {
+            Ok(ping) => healthy(&self.send(ping, token.as_slice(), 1)),
+            Err(_) => false
         }
-        Ok(try!(stream.send()))
+    }
+
+    // pings every host each `interval` on a task of its own, which holds a
+    // copy of this client as it is now and stops once the returned handle is
+    // dropped
+    pub fn start_health_check(&self, interval: Duration) -> HealthCheck {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let mut client = self.clone();
+        client.health_check = None;
+
+        spawn(proc() {
+            while !stopped.load(SeqCst) {
+                for host in client.hosts.all().iter() {
+                    client.hosts.mark(host.as_slice(),
+                                      client.check(host.as_slice()));
+                }
+                timer::sleep(interval);
+            }
+        });
+
+        HealthCheck { stop: stop, interval: interval }
+    }
+
+    fn render(&self, req: &RequestBuilder)
+              -> Result<RenderedRequest, OrchestrateError> {
+        let request = try!(self.build(self.hosts.active().as_slice(), "", req));
+
+        // the HTTP transport would compress the body on its way out
+        let compressed = match request.body {
+            Some(ref body) if request.gzip => Some(try!(compress(body.as_bytes()))),
+            _ => None
+        };
+
+        let mut headers: Vec<(String, String)> =
+                request.headers.into_iter().map(|(name, value)| {
+            if name.as_slice().eq_ignore_ascii_case("Authorization") {
+                (name, REDACTED.to_string())
+            } else if name.as_slice() == "Content-Length" && compressed.is_some() {
+                (name, compressed.as_ref().unwrap().len().to_string())
+            } else {
+                (name, value)
+            }
+        }).collect();
+        if compressed.is_some() {
+            headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+        }
+
+        Ok(RenderedRequest {
+            method: request.method,
+            url: request.url.to_string(),
+            headers: headers,
+            body: request.body,
+            gzip: compressed.is_some()
+        })
+    }
+
+    // runs the request on one of the client's async threads and decodes the
+    // response there
+    pub fn exec_async<T: Send>(&self, req: RequestBuilder,
+                               decode: proc(Response): Send
+                                           -> Result<T, OrchestrateError>)
+                               -> Future<Result<T, OrchestrateError>> {
+        self.exec_async_in(req, proc(_, res) decode(res))
+    }
+
+    // `exec_async` handing the decoder the collection as with `exec_in`
+    pub fn exec_async_in<T: Send>(&self, req: RequestBuilder,
+                                  decode: proc(String, Response): Send
+                                              -> Result<T, OrchestrateError>)
+                                  -> Future<Result<T, OrchestrateError>> {
+        let client = self.clone();
+        let (tx, rx) = channel();
+        self.tasks.execute(proc() {
+            tx.send(match client.exec_in(&req) {
+                Ok((collection, res)) => decode(collection, res),
+                Err(err) => Err(err)
+            })
+        });
+        Future::from_receiver(rx)
+    }
+
+    fn send(&self, request: Request, token: &str, attempt: uint)
+            -> Result<Response, OrchestrateError> {
+        let observer = match self.observer {
+            Some(ref observer) => observer,
+            None => return self.transport.send(request)
+        };
+
+        let bodies = observer.wants_bodies();
+        let mut exchange = Exchange {
+            method: request.method.clone(),
+            url: redact(request.url.to_string().as_slice(), token),
+            request_headers: request.headers.iter().map(|&(ref name, ref value)| {
+                if name.as_slice().eq_ignore_ascii_case("Authorization") {
+                    (name.clone(), REDACTED.to_string())
+                } else {
+                    (name.clone(), redact(value.as_slice(), token))
+                }
+            }).collect(),
+            request_body: if bodies { request.body.clone() } else { None },
+            status: None,
+            response_body: None,
+            error: None,
+            latency: Duration::zero(),
+            attempt: attempt
+        };
+
+        let started = time::precise_time_ns();
+        let mut result = self.transport.send(request);
+
+        // buffer the body so it can be both observed and handed back
+        if bodies {
+            result = match result {
+                Ok(mut res) => match res.read_to_end() {
+                    Ok(body) => {
+                        exchange.response_body = String::from_utf8(body.clone()).ok();
+                        Ok(Response::new(res.status, res.headers.clone(),
+                                         box MemReader::new(body)))
+                    },
+                    Err(err) => Err(::std::error::FromError::from_error(err))
+                },
+                Err(err) => Err(err)
+            };
+        }
+
+        exchange.latency = elapsed(started);
+        match result {
+            Ok(ref res) => exchange.status = Some(res.status),
+            Err(ref err) => exchange.error = Some(err.to_string())
+        }
+
+        observer.observe(&exchange);
+        result
+    }
+
+    // pagination links are absolute paths, or urls, as the server sees them;
+    // behind a proxy only the end of our prefix may be part of them, so
+    // strip as much of it as the link starts with
+    pub fn relative_link(&self, link: &str) -> String {
+        let path = match link.find_str("://") {
+            Some(i) => {
+                let rest = link.slice_from(i + 3);
+                rest.find('/').map_or("", |j| rest.slice_from(j))
+            },
+            None => link
+        };
+        let path = path.trim_left_chars('/');
+
+        let segments: Vec<&str> = self.prefix.as_slice()
+                                             .split('/')
+                                             .filter(|s| !s.is_empty())
+                                             .collect();
+        for i in range(0, segments.len()) {
+            let tail = segments.slice_from(i).connect("/");
+            let rest = if path.starts_with(tail.as_slice()) {
+                path.slice_from(tail.len())
+            } else {
+                continue
+            };
+            if rest.is_empty() || rest.starts_with("/") || rest.starts_with("?") {
+                return rest.trim_left_chars('/').to_string();
+            }
+        }
+
+        path.to_string()
+    }
+
+    fn build(&self, host: &str, token: &str, req: &RequestBuilder)
+             -> Result<Request, OrchestrateError> {
+        let port = self.port.map_or(String::new(), |port| format!(":{}", port));
+        let prefix = if self.prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", self.prefix)
+        };
+        let url = format!("{}://{}{}/{}{}", self.scheme, host, port, prefix,
+                          req.path);
+        let mut url = match Url::parse(url.as_slice()) {
+            Ok(url) => url,
+            Err(err) => return Err(OrchestrateError::Validation(
+                format!("invalid url {}: {}", url, err)))
+        };
+
+        if !req.query.is_empty() {
+          url.query = Some(serialize_owned(req.query.as_slice()));
+        }
+
+        let credentials = format!("{}:", token);
+        let mut headers = vec![
+            ("User-Agent".to_string(), self.user_agent.to_string()),
+            ("Accept-Encoding".to_string(), "gzip".to_string()),
+            ("Authorization".to_string(),
+             format!("Basic {}", credentials.as_bytes().to_base64(STANDARD)))
+        ];
+
+        for (name, value) in req.headers.iter() {
+            headers.push((name.to_string(), value.to_string()));
+        }
+
+        match req.body {
+            Some(ref body) => {
+                headers.push(("Content-Length".to_string(),
+                              body.len().to_string()));
+                headers.push(("Content-Type".to_string(),
+                              self.content_type.to_string()));
+
+            },
+            None => headers.push(("Content-Length".to_string(),
+                                  "0".to_string()))
+        }
+
+        // only documents are worth compressing
+        let document = match req.operation {
+            KvCreate | KvUpdate | EventsCreate => true,
+            _ => false
+        };
+        let gzip = document && req.body.as_ref().map_or(false, |body| {
+            self.gzip_threshold.map_or(false, |min| body.len() >= min)
+        });
+
+        Ok(Request {
+            method: req.method.clone(),
+            url: url,
+            headers: headers,
+            body: req.body.clone(),
+            gzip: gzip,
+            retryable: req.is_retryable(),
+            timeouts: self.timeouts.clone()
+        })
+    }
+}
+
+fn api_error(req: &RequestBuilder, mut res: Response) -> OrchestrateError {
+    let body = match res.read_to_string() {
+        Ok(body) => body,
+        Err(err) => return ::std::error::FromError::from_error(err)
+    };
+    let headers = res.headers.iter()
+                             .map(|header| (header.name().to_string(),
+                                            header.value_string()))
+                             .collect();
+
+    OrchestrateError::ApiError(ApiError::new(req.operation.clone(),
+                                             req.method.clone(),
+                                             req.path.clone(),
+                                             res.status, headers, body))
+}
+
+fn redact(text: &str, token: &str) -> String {
+    if token.is_empty() {
+        text.to_string()
+    } else {
+        text.replace(token, REDACTED)
+    }
+}
+
+pub struct HealthCheck {
+    stop: Arc<AtomicBool>,
+    pub interval: Duration
+}
+
+impl Drop for HealthCheck {
+    fn drop(&mut self) {
+        self.stop.store(true, SeqCst);
+    }
+}
+
+// anything short of a server error means the server is up
+fn healthy(result: &Result<Response, OrchestrateError>) -> bool {
+    match *result {
+        Ok(ref res) => (res.status as u16) < 500,
+        Err(_) => false
+    }
+}
+
+impl fmt::Show for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Client {{ host: {}, token: {}, user_agent: {} }}",
+               self.hosts.active(), REDACTED, self.user_agent)
+    }
+}
+
+// The threads running `exec_async` operations, started on first use and
+// shared by every clone of the client.
+#[deriving(Clone)]
+pub struct Tasks {
+    size: uint,
+    pool: Arc<Mutex<Option<TaskPool>>>
+}
+
+impl Tasks {
+    pub fn new(size: uint) -> Tasks {
+        Tasks {
+            size: size,
+            pool: Arc::new(Mutex::new(None))
+        }
+    }
+
+    // jobs wait in line once every thread is busy
+    fn execute(&self, job: proc(): Send) {
+        let mut pool = self.pool.lock();
+        if pool.is_none() {
+            *pool = Some(TaskPool::new(self.size));
+        }
+        pool.as_ref().unwrap().execute(job);
+    }
+}
+
+struct Metered {
+    body: Response,
+    metric: Metric,
+    metrics: Arc<Box<MetricsSink + Send + Sync>>
+}
+
+impl Reader for Metered {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let read = try!(self.body.read(buf));
+        self.metric.response_bytes += read;
+        Ok(read)
+    }
+}
+
+impl Drop for Metered {
+    fn drop(&mut self) {
+        self.metrics.record(&self.metric);
+    }
+}
+
+fn elapsed(since: u64) -> Duration {
+    Duration::nanoseconds((time::precise_time_ns() - since) as i64)
+}
+
+fn shortest(timeout: Option<Duration>, remaining: Duration) -> Duration {
+    match timeout {
+        Some(timeout) => cmp::min(timeout, remaining),
+        None => remaining
     }
 }
 
@@ -110,3 +773,31 @@ pub fn version() -> String {
                                  env!("CARGO_PKG_VERSION_MINOR"),
                                  env!("CARGO_PKG_VERSION_PATCH")))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Client;
+
+    #[test]
+    fn relative_link_strips_the_host_and_prefix() {
+        let client = Client::new("key");
+        assert_eq!(client.relative_link("/v0/users?limit=10&offset=10").as_slice(),
+                   "users?limit=10&offset=10");
+        assert_eq!(client.relative_link("https://api.orchestrate.io/v0/users?offset=10")
+                         .as_slice(),
+                   "users?offset=10");
+        assert_eq!(client.relative_link("users?offset=10").as_slice(),
+                   "users?offset=10");
+    }
+
+    #[test]
+    fn relative_link_matches_the_end_of_the_prefix() {
+        let mut client = Client::new("key");
+        client.prefix = "orchestrate/v0".to_string();
+        assert_eq!(client.relative_link("/orchestrate/v0/users?offset=10").as_slice(),
+                   "users?offset=10");
+        assert_eq!(client.relative_link("/v0/users?offset=10").as_slice(),
+                   "users?offset=10");
+        assert_eq!(client.relative_link("/v0users").as_slice(), "v0users");
+    }
+}
@@ -0,0 +1,203 @@
+use error::OrchestrateError;
+use error::OrchestrateError::Credentials;
+use std::collections::HashMap;
+use std::io::{File, IoError};
+use std::io::fs;
+use std::os;
+use std::sync::Mutex;
+
+// Supplies the API key for each request, so keys can be rotated while the
+// client is running. `collection` is empty for requests that don't target
+// one, such as `ping`.
+pub trait CredentialProvider {
+    fn token(&self, collection: &str) -> Result<String, OrchestrateError>;
+}
+
+pub struct StaticCredentials {
+    token: String
+}
+
+impl StaticCredentials {
+    pub fn new(token: &str) -> StaticCredentials {
+        StaticCredentials { token: token.to_string() }
+    }
+}
+
+impl CredentialProvider for StaticCredentials {
+    fn token(&self, _: &str) -> Result<String, OrchestrateError> {
+        Ok(self.token.clone())
+    }
+}
+
+// Reads the key from an environment variable on every request.
+pub struct EnvCredentials {
+    name: String
+}
+
+impl EnvCredentials {
+    pub fn new(name: &str) -> EnvCredentials {
+        EnvCredentials { name: name.to_string() }
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn token(&self, _: &str) -> Result<String, OrchestrateError> {
+        match os::getenv(self.name.as_slice()) {
+            Some(ref token) if !token.is_empty() => Ok(token.as_slice().trim().to_string()),
+            _ => Err(Credentials(format!("environment variable {} is not set",
+                                         self.name)))
+        }
+    }
+}
+
+// Reads the key from a file, reading it again whenever it is modified.
+pub struct FileCredentials {
+    path: Path,
+    cached: Mutex<Option<(u64, String)>>
+}
+
+impl FileCredentials {
+    pub fn new(path: Path) -> FileCredentials {
+        FileCredentials {
+            path: path,
+            cached: Mutex::new(None)
+        }
+    }
+}
+
+impl CredentialProvider for FileCredentials {
+    fn token(&self, _: &str) -> Result<String, OrchestrateError> {
+        // a missing or unreadable file won't fix itself on a retry
+        let unreadable = |err: IoError| {
+            Credentials(format!("can't read API key from {}: {}",
+                                self.path.display(), err))
+        };
+
+        let modified = try!(fs::stat(&self.path).map_err(|err| unreadable(err)))
+                           .modified;
+        let mut cached = self.cached.lock();
+
+        match *cached {
+            Some((when, ref token)) if when == modified => return Ok(token.clone()),
+            _ => {}
+        }
+
+        let contents = try!(File::open(&self.path).read_to_string()
+                                                  .map_err(|err| unreadable(err)));
+        let token = contents.as_slice().trim().to_string();
+        if token.is_empty() {
+            return Err(Credentials(format!("no API key in {}",
+                                           self.path.display())));
+        }
+
+        *cached = Some((modified, token.clone()));
+        Ok(token)
+    }
+}
+
+// Asks a function for the key of each collection.
+pub struct FnCredentials {
+    provide: fn(&str) -> Result<String, OrchestrateError>
+}
+
+impl FnCredentials {
+    pub fn new(provide: fn(&str) -> Result<String, OrchestrateError>)
+               -> FnCredentials {
+        FnCredentials { provide: provide }
+    }
+}
+
+impl CredentialProvider for FnCredentials {
+    fn token(&self, collection: &str) -> Result<String, OrchestrateError> {
+        (self.provide)(collection)
+    }
+}
+
+// Uses a different provider for some collections, falling back to a default
+// for the rest.
+pub struct CollectionCredentials {
+    default: Box<CredentialProvider + Send + Sync>,
+    collections: HashMap<String, Box<CredentialProvider + Send + Sync>>
+}
+
+impl CollectionCredentials {
+
+    pub fn new<P: CredentialProvider + Send + Sync>(default: P)
+                                                    -> CollectionCredentials {
+        CollectionCredentials {
+            default: box default as Box<CredentialProvider + Send + Sync>,
+            collections: HashMap::new()
+        }
+    }
+
+    pub fn collection<P: CredentialProvider + Send + Sync>(mut self,
+                                                           collection: &str,
+                                                           provider: P)
+                                                           -> CollectionCredentials {
+        self.collections.insert(collection.to_string(),
+                                box provider as Box<CredentialProvider + Send + Sync>);
+        self
+    }
+}
+
+impl CredentialProvider for CollectionCredentials {
+    fn token(&self, collection: &str) -> Result<String, OrchestrateError> {
+        match self.collections.get(collection) {
+            Some(provider) => provider.token(collection),
+            None => self.default.token(collection)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CredentialProvider, EnvCredentials, FileCredentials,
+                StaticCredentials, CollectionCredentials};
+    use Orchestrate;
+    use error::ErrorKind;
+    use transport::MemoryTransport;
+    use serialize::base64::{ToBase64, STANDARD};
+    use std::os;
+
+    fn basic(token: &str) -> String {
+        format!("Basic {}", format!("{}:", token).as_bytes().to_base64(STANDARD))
+    }
+
+    #[test]
+    fn picks_up_a_rotated_key() {
+        let transport = MemoryTransport::new();
+        transport.respond(200, "true");
+        transport.respond(200, "true");
+        let mut client = Orchestrate::new("");
+        client.set_transport(transport.clone());
+        client.set_credentials(EnvCredentials::new("ORC_TEST_ROTATED_KEY"));
+
+        os::setenv("ORC_TEST_ROTATED_KEY", "old");
+        client.ping().unwrap();
+        os::setenv("ORC_TEST_ROTATED_KEY", "new");
+        client.ping().unwrap();
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].header("Authorization"), Some(basic("old").as_slice()));
+        assert_eq!(requests[1].header("Authorization"), Some(basic("new").as_slice()));
+    }
+
+    #[test]
+    fn missing_keys_are_not_retryable() {
+        let err = EnvCredentials::new("ORC_TEST_UNSET_KEY").token("").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Credentials);
+
+        let path = os::tmpdir().join("orchestrate-no-such-key-file");
+        let err = FileCredentials::new(path).token("").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Credentials);
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn collections_can_have_their_own_key() {
+        let credentials = CollectionCredentials::new(StaticCredentials::new("default"))
+            .collection("admins", StaticCredentials::new("admin"));
+        assert_eq!(credentials.token("admins").unwrap().as_slice(), "admin");
+        assert_eq!(credentials.token("users").unwrap().as_slice(), "default");
+    }
+}
@@ -0,0 +1,76 @@
+use hyper::method::{Method, Head};
+use std::fmt;
+
+// The request a builder would have sent, returned as
+// `OrchestrateError::DryRun` when dry-run mode is on. The API key is never
+// part of it.
+#[deriving(Clone)]
+pub struct RenderedRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    // shown decoded even when it would be sent gzipped
+    pub body: Option<String>,
+    pub gzip: bool
+}
+
+impl RenderedRequest {
+
+    // an equivalent curl command, reading the API key from $ORC_API_KEY
+    pub fn curl(&self) -> String {
+        let gzip = match self.body {
+            Some(ref body) if self.gzip => {
+                format!("printf '%s' {} | gzip | ", quote(body.as_slice()))
+            },
+            _ => String::new()
+        };
+        let mut out = match self.method {
+            Head => format!("{}curl --head", gzip),
+            ref method => format!("{}curl -X {}", gzip, method)
+        };
+        out.push_str(format!(" {}", quote(self.url.as_slice())).as_slice());
+        out.push_str(" -u \"$ORC_API_KEY:\"");
+
+        // curl sets these itself, and decodes the response with --compressed
+        out.push_str(" --compressed");
+        for &(ref name, ref value) in self.headers.iter() {
+            if name.as_slice() == "Authorization" ||
+               name.as_slice() == "Content-Length" ||
+               name.as_slice() == "Accept-Encoding" {
+                continue;
+            }
+            out.push_str(format!(" -H {}",
+                                 quote(format!("{}: {}", name, value).as_slice()))
+                             .as_slice());
+        }
+
+        match self.body {
+            Some(_) if self.gzip => out.push_str(" --data-binary @-"),
+            Some(ref body) => {
+                out.push_str(format!(" --data-binary {}",
+                                     quote(body.as_slice())).as_slice());
+            },
+            None => {}
+        }
+
+        out
+    }
+}
+
+// renders the request as it would go over the wire
+impl fmt::Show for RenderedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "{} {}", self.method, self.url));
+        for &(ref name, ref value) in self.headers.iter() {
+            try!(writeln!(f, "{}: {}", name, value));
+        }
+        match self.body {
+            Some(ref body) => write!(f, "\n{}", body),
+            None => Ok(())
+        }
+    }
+}
+
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace("'", "'\\''"))
+}
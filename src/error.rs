@@ -1,14 +1,108 @@
 use serialize::json;
+use serialize::json::Json;
 use hyper;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use operation::Operation;
+use std::ascii::AsciiExt;
 use std::io;
 use std::error;
+use std::fmt;
+use std::time::Duration;
+use dry_run::RenderedRequest;
+use retry::parse_retry_after;
 
 #[deriving(Show)]
 pub enum OrchestrateError {
     JsonError(json::DecoderError),
     HttpError(hyper::HttpError),
-    RequestError(String),
-    IoError(io::IoError)
+    // the request was rejected before being sent
+    Validation(String),
+    // the server answered in a way the client doesn't understand
+    Protocol(String),
+    // no API key could be had from the credential provider
+    Credentials(String),
+    // a replay cassette or memory transport had nothing to answer with
+    NoResponse(String),
+    ApiError(ApiError),
+    IoError(io::IoError),
+    Timeout,
+    Throttled(Duration),
+    CircuitOpen,
+    DryRun(RenderedRequest)
+}
+
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ErrorKind {
+    NotFound,
+    // If-Match given a ref that is no longer current
+    RefMismatch,
+    // If-None-Match: * on a key that already exists
+    AlreadyExists,
+    Unauthorized,
+    // 429 from the server
+    RateLimited,
+    ServerError,
+    // any other 4xx
+    BadRequest,
+    Timeout,
+    Network,
+    Decode,
+    // the client-side rate limiter ran out of budget
+    Throttled,
+    CircuitOpen,
+    DryRun,
+    Validation,
+    Protocol,
+    Credentials,
+    NoResponse,
+    Other
+}
+
+impl OrchestrateError {
+
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            OrchestrateError::ApiError(ref err) => err.kind(),
+            OrchestrateError::JsonError(_) => ErrorKind::Decode,
+            OrchestrateError::HttpError(hyper::HttpError::HttpIoError(_)) |
+            OrchestrateError::IoError(_) => ErrorKind::Network,
+            OrchestrateError::Timeout => ErrorKind::Timeout,
+            OrchestrateError::Throttled(_) => ErrorKind::Throttled,
+            OrchestrateError::CircuitOpen => ErrorKind::CircuitOpen,
+            OrchestrateError::DryRun(_) => ErrorKind::DryRun,
+            OrchestrateError::Validation(_) => ErrorKind::Validation,
+            OrchestrateError::Protocol(_) => ErrorKind::Protocol,
+            OrchestrateError::Credentials(_) => ErrorKind::Credentials,
+            OrchestrateError::NoResponse(_) => ErrorKind::NoResponse,
+            _ => ErrorKind::Other
+        }
+    }
+
+    // whether the same request may succeed if sent again later
+    pub fn is_retryable(&self) -> bool {
+        match self.kind() {
+            ErrorKind::RateLimited | ErrorKind::Timeout | ErrorKind::Network |
+            ErrorKind::Throttled | ErrorKind::CircuitOpen => true,
+            ErrorKind::ServerError => match *self {
+                OrchestrateError::ApiError(ref err) => err.status as u16 != 501,
+                _ => true
+            },
+            _ => false
+        }
+    }
+
+    // how long the server, or the client-side rate limiter, asked to wait
+    pub fn retry_after(&self) -> Option<Duration> {
+        match *self {
+            OrchestrateError::ApiError(ref err) => {
+                err.header("Retry-After")
+                   .and_then(|value| parse_retry_after(value))
+            },
+            OrchestrateError::Throttled(wait) => Some(wait),
+            _ => None
+        }
+    }
 }
 
 impl error::Error for OrchestrateError {
@@ -16,24 +110,147 @@ impl error::Error for OrchestrateError {
         match *self {
             OrchestrateError::JsonError(_) => "failed to decode json",
             OrchestrateError::HttpError(ref err) => err.description(),
-            OrchestrateError::RequestError(ref err) => err.as_slice(),
-            OrchestrateError::IoError(ref err) => err.description()
+            OrchestrateError::Validation(ref err) => err.as_slice(),
+            OrchestrateError::Protocol(ref err) => err.as_slice(),
+            OrchestrateError::Credentials(ref err) => err.as_slice(),
+            OrchestrateError::NoResponse(ref err) => err.as_slice(),
+            OrchestrateError::ApiError(ref err) => {
+                err.message.as_ref().map_or("request failed", |m| m.as_slice())
+            },
+            OrchestrateError::IoError(ref err) => err.description(),
+            OrchestrateError::Timeout => "request timed out",
+            OrchestrateError::Throttled(_) => "client-side rate limit exhausted",
+            OrchestrateError::CircuitOpen => "circuit breaker is open",
+            OrchestrateError::DryRun(_) => "dry run, request not sent"
         }
     }
 
     fn detail(&self) -> Option<String> {
         match *self {
+            OrchestrateError::JsonError(ref err) => Some(err.to_string()),
+            OrchestrateError::HttpError(ref err) => err.detail(),
+            OrchestrateError::IoError(ref err) => err.detail.clone(),
+            OrchestrateError::ApiError(ref err) => Some(err.to_string()),
+            OrchestrateError::Throttled(wait) => {
+                Some(format!("next request allowed in {}", wait))
+            },
+            OrchestrateError::DryRun(ref req) => Some(req.to_string()),
             _ => None
         }
     }
 
     fn cause(&self) -> Option<&error::Error> {
         match *self {
+            OrchestrateError::HttpError(ref err) => Some(err as &error::Error),
+            OrchestrateError::IoError(ref err) => Some(err as &error::Error),
             _ => None
         }
     }
 }
 
+pub fn require<T>(value: Option<T>, what: &str) -> Result<T, OrchestrateError> {
+    match value {
+        Some(value) => Ok(value),
+        None => Err(OrchestrateError::Validation(format!("{} is required", what)))
+    }
+}
+
+// a success status other than the one the operation answers with
+pub fn unexpected_status(expected: u16, status: StatusCode) -> OrchestrateError {
+    OrchestrateError::Protocol(format!("expected status {}, got {}",
+                                       expected, status))
+}
+
+// A response from Orchestrate with a status other than 2xx.
+#[deriving(Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub operation: Operation,
+    pub method: Method,
+    // relative to the base url, as in the request
+    pub path: String,
+    pub message: Option<String>,
+    pub code: Option<String>,
+    pub details: Option<Json>,
+    pub headers: Vec<(String, String)>,
+    pub body: String
+}
+
+impl ApiError {
+
+    // message, code and details come from the body when it is an Orchestrate
+    // error document, the raw body is kept either way
+    pub fn new(operation: Operation, method: Method, path: String,
+               status: StatusCode, headers: Vec<(String, String)>, body: String)
+               -> ApiError {
+        let payload = json::from_str(body.as_slice()).ok();
+        let field = |name: &str| {
+            payload.as_ref()
+                   .and_then(|payload| payload.find(name))
+                   .and_then(|value| value.as_string())
+                   .map(|value| value.to_string())
+        };
+
+        ApiError {
+            status: status,
+            operation: operation,
+            method: method,
+            path: path,
+            message: field("message"),
+            code: field("code"),
+            details: payload.as_ref()
+                            .and_then(|payload| payload.find("details"))
+                            .map(|details| details.clone()),
+            headers: headers,
+            body: body
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match (self.status as u16, self.code.as_ref().map(|c| c.as_slice())) {
+            (404, _) => ErrorKind::NotFound,
+            (412, Some("item_already_present")) => ErrorKind::AlreadyExists,
+            (412, _) => ErrorKind::RefMismatch,
+            (401, _) | (403, _) => ErrorKind::Unauthorized,
+            (429, _) => ErrorKind::RateLimited,
+            (status, _) if status >= 500 => ErrorKind::ServerError,
+            (status, _) if status >= 400 => ErrorKind::BadRequest,
+            _ => ErrorKind::Other
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+                    .find(|&&(ref n, _)| n.as_slice().eq_ignore_ascii_case(name))
+                    .map(|&(_, ref v)| v.as_slice())
+    }
+
+    // the id Orchestrate gave the request, for support
+    pub fn request_id(&self) -> Option<&str> {
+        self.header("X-ORCHESTRATE-REQ-ID")
+    }
+}
+
+impl fmt::Show for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} {} failed with {}", self.method, self.path,
+                    self.status));
+        match self.code {
+            Some(ref code) => try!(write!(f, " ({})", code)),
+            None => {}
+        }
+        match self.message {
+            Some(ref message) => try!(write!(f, ": {}", message)),
+            None => {}
+        }
+        match self.request_id() {
+            Some(id) => try!(write!(f, " [request id {}]", id)),
+            None => {}
+        }
+        Ok(())
+    }
+}
+
 impl error::FromError<json::DecoderError> for OrchestrateError {
     fn from_error(err: json::DecoderError) -> OrchestrateError {
         OrchestrateError::JsonError(err)
@@ -42,12 +259,122 @@ impl error::FromError<json::DecoderError> for OrchestrateError {
 
 impl error::FromError<hyper::HttpError> for OrchestrateError {
     fn from_error(err: hyper::HttpError) -> OrchestrateError {
-        OrchestrateError::HttpError(err)
+        match err {
+            hyper::HttpError::HttpIoError(ref err) if err.kind == io::TimedOut => {
+                OrchestrateError::Timeout
+            },
+            err => OrchestrateError::HttpError(err)
+        }
     }
 }
 
 impl error::FromError<io::IoError> for OrchestrateError {
     fn from_error(err: io::IoError) -> OrchestrateError {
-        OrchestrateError::IoError(err)
+        match err.kind {
+            io::TimedOut => OrchestrateError::Timeout,
+            _ => OrchestrateError::IoError(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OrchestrateError, ErrorKind};
+    use Orchestrate;
+    use transport::MemoryTransport;
+    use operation::Operation::KvGet;
+    use hyper::method::Get;
+    use std::time::Duration;
+
+    #[deriving(Decodable, Show)]
+    struct User {
+        name: String
+    }
+
+    fn get(transport: &MemoryTransport) -> OrchestrateError {
+        let mut client = Orchestrate::new("key");
+        client.set_transport(transport.clone());
+        client.get("users", "bob").exec::<User>().unwrap_err()
+    }
+
+    #[test]
+    fn errors_carry_the_status() {
+        let transport = MemoryTransport::new();
+        transport.respond_with_headers(404, vec![("X-ORCHESTRATE-REQ-ID", "req-1")],
+                                       "{\"message\": \"not found\", \
+                                        \"code\": \"items_not_found\"}");
+
+        let err = get(&transport);
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        match err {
+            OrchestrateError::ApiError(ref err) => {
+                assert!(err.operation == KvGet);
+                assert_eq!(err.method, Get);
+                assert_eq!(err.path.as_slice(), "users/bob");
+                assert_eq!(err.message, Some("not found".to_string()));
+                assert_eq!(err.code, Some("items_not_found".to_string()));
+                assert_eq!(err.request_id(), Some("req-1"));
+            },
+            err => panic!("expected an api error, got {}", err)
+        }
+    }
+
+    #[test]
+    fn keeps_bodies_that_are_not_error_documents() {
+        let transport = MemoryTransport::new();
+        transport.respond(502, "Bad Gateway");
+
+        match get(&transport) {
+            OrchestrateError::ApiError(ref err) => {
+                assert_eq!(err.message, None);
+                assert_eq!(err.body.as_slice(), "Bad Gateway");
+            },
+            err => panic!("expected an api error, got {}", err)
+        }
+    }
+
+    #[test]
+    fn classifies_statuses_and_codes() {
+        let kind = |status: u16, body: &str| {
+            let transport = MemoryTransport::new();
+            transport.respond(status, body);
+            get(&transport).kind()
+        };
+        assert_eq!(kind(412, "{\"code\": \"item_already_present\"}"),
+                   ErrorKind::AlreadyExists);
+        assert_eq!(kind(412, "{\"code\": \"item_version_mismatch\"}"),
+                   ErrorKind::RefMismatch);
+        assert_eq!(kind(401, ""), ErrorKind::Unauthorized);
+        assert_eq!(kind(400, ""), ErrorKind::BadRequest);
+        assert_eq!(kind(503, ""), ErrorKind::ServerError);
+    }
+
+    #[test]
+    fn rate_limits_are_retried_after_the_given_delay() {
+        let transport = MemoryTransport::new();
+        transport.respond_with_headers(429, vec![("Retry-After", "30")], "");
+
+        let err = get(&transport);
+        assert_eq!(err.kind(), ErrorKind::RateLimited);
+        assert!(err.is_retryable());
+        assert_eq!(err.retry_after(), Some(Duration::seconds(30)));
+    }
+
+    #[test]
+    fn client_errors_are_not_retryable() {
+        let transport = MemoryTransport::new();
+        transport.respond(404, "");
+        assert!(!get(&transport).is_retryable());
+
+        let transport = MemoryTransport::new();
+        transport.respond(501, "");
+        assert!(!get(&transport).is_retryable());
+    }
+
+    #[test]
+    fn empty_memory_transport_has_no_response() {
+        let err = get(&MemoryTransport::new());
+        assert_eq!(err.kind(), ErrorKind::NoResponse);
+        assert!(!err.is_retryable());
     }
 }
@@ -1,11 +1,16 @@
-use client::Client;
+use client::{Client, RequestBuilder};
+use transport::Response;
 use error::OrchestrateError;
-use error::OrchestrateError::RequestError;
-use RepresentsJSON;
+use error::{require, unexpected_status};
+use {RepresentsJSON, OrchestrateFuture};
+use stream::ResultStream;
+use std::time::Duration;
+use std::sync::{Arc, Future};
 use serialize::{json, Encodable};
 use serialize::json::Encoder;
 use std::io::IoError;
-use hyper::method::{Get, Post, Delete};
+use operation::Operation::{EventsList, EventsCreate, EventsDelete};
+use names::Segment::{Collection, Key, Kind, Literal};
 
 #[deriving(Decodable, Encodable, Show)]
 pub struct EventResults<T> {
@@ -20,19 +25,24 @@ pub struct EventResult<T> {
     pub value: T
 }
 
-pub struct CreateEvent<'a> {
-    client: &'a mut Client,
+pub struct CreateEvent {
+    client: Arc<Client>,
     url: String,
+    request: RequestBuilder,
     data: Option<String>,
     timestamp: Option<String>
 }
 
-impl<'a> CreateEvent<'a> {
-    pub fn new(client: &'a mut Client, collection: &str, key: &str, kind: &str)
-               -> CreateEvent<'a> {
+impl CreateEvent {
+    pub fn new(client: Arc<Client>, collection: &str, key: &str, kind: &str)
+               -> CreateEvent {
+        let request = RequestBuilder::with_path(EventsCreate, collection,
+                                                &[Collection(collection), Key(key),
+                                                  Literal("events"), Kind(kind)]);
         CreateEvent {
             client: client,
-            url: format!("{}/{}/events/{}", collection, key, kind),
+            url: request.path.clone(),
+            request: request,
             data: None,
             timestamp: None
         }
@@ -41,158 +51,226 @@ impl<'a> CreateEvent<'a> {
     pub fn data<'b,
                 T: Encodable<Encoder<'b>, IoError>>(
                     mut self,
-                    data: &T) -> CreateEvent<'a> {
+                    data: &T) -> CreateEvent {
         self.data = Some(json::encode(&data));
         self
     }
 
-    pub fn timestamp(mut self, time: u64) -> CreateEvent<'a> {
+    pub fn timestamp(mut self, time: u64) -> CreateEvent {
         self.timestamp = Some(time.to_string());
         self
     }
 
+    pub fn retryable(mut self) -> CreateEvent {
+        self.request.retryable(true);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> CreateEvent {
+        self.request.timeout(timeout);
+        self
+    }
+
     pub fn exec(self) -> Result<bool, OrchestrateError> {
-        let CreateEvent { client, mut url, data, timestamp } = self;
+        let (client, request) = try!(self.prepare());
+        decode_create(try!(client.exec(&request)))
+    }
 
-        if timestamp.is_some() {
-            let parts = vec![url, timestamp.unwrap()];
-            url = parts.connect("/");
-        }
+    pub fn exec_async(self) -> OrchestrateFuture<bool> {
+        let (client, request) = match self.prepare() {
+            Ok(prepared) => prepared,
+            Err(err) => return Future::from_value(Err(err))
+        };
+        client.exec_async(request, proc(res) decode_create(res))
+    }
 
-        let mut res = try!(client.trailing(url.as_slice())
-                                 .body(data.unwrap().as_slice())
-                                 .method(Post)
-                                 .exec());
-        let body = try!(res.read_to_string());
+    fn prepare(self) -> Result<(Arc<Client>, RequestBuilder), OrchestrateError> {
+        let CreateEvent { client, mut url, mut request, data, timestamp } = self;
+        let data = try!(require(data, "data"));
 
-        if (res.status as i32) != 201 {
-            return Err(RequestError(body));
+        match timestamp {
+            Some(timestamp) => url = vec![url, timestamp].connect("/"),
+            None => {}
         }
 
-        Ok(true)
+        request.trailing(url.as_slice()).body(data.as_slice());
+        Ok((client, request))
     }
 }
 
-pub struct DeleteEvent<'a> {
-    client: &'a mut Client,
+fn decode_create(mut res: Response) -> Result<bool, OrchestrateError> {
+    try!(res.read_to_end());
+
+    if (res.status as i32) != 201 {
+        return Err(unexpected_status(201, res.status));
+    }
+
+    Ok(true)
+}
+
+pub struct DeleteEvent {
+    client: Arc<Client>,
     url: String,
+    request: RequestBuilder,
     timestamp: Option<String>,
-    ordinal: Option<String>,
-    ref_: Option<String>
+    ordinal: Option<String>
 }
 
-impl<'a> DeleteEvent<'a> {
+impl DeleteEvent {
 
-    pub fn new(client: &'a mut Client, collection: &str, key: &str, kind: &str)
-               -> DeleteEvent<'a> {
+    pub fn new(client: Arc<Client>, collection: &str, key: &str, kind: &str)
+               -> DeleteEvent {
+        let request = RequestBuilder::with_path(EventsDelete, collection,
+                                                &[Collection(collection), Key(key),
+                                                  Literal("events"), Kind(kind)]);
         DeleteEvent {
             client: client,
-            url: format!("{}/{}/events/{}", collection, key, kind),
+            url: request.path.clone(),
+            request: request,
             timestamp: None,
-            ordinal: None,
-            ref_: None
+            ordinal: None
         }
     }
 
-    pub fn timestamp(mut self, time: u64) -> DeleteEvent<'a> {
+    pub fn timestamp(mut self, time: u64) -> DeleteEvent {
         self.timestamp = Some(time.to_string());
         self
     }
 
-    pub fn ordinal(mut self, ordinal: u64) -> DeleteEvent<'a> {
+    pub fn ordinal(mut self, ordinal: u64) -> DeleteEvent {
         self.ordinal = Some(ordinal.to_string());
         self
     }
 
-    pub fn if_match(mut self, ref_: &str) -> DeleteEvent<'a> {
-        self.ref_ = Some(ref_.to_string());
+    pub fn if_match(mut self, ref_: &str) -> DeleteEvent {
+        self.request.header("If-Match", ref_);
         self
     }
 
-    pub fn purge(mut self) -> DeleteEvent<'a> {
-        self.client.query("purge", "true");
+    pub fn purge(mut self) -> DeleteEvent {
+        self.request.query("purge", "true");
         self
     }
 
-    pub fn exec(self) -> Result<bool, OrchestrateError> {
-        let DeleteEvent { mut client, mut url, timestamp, ordinal, ref_ } = self;
-        url = vec![url, timestamp.unwrap(), ordinal.unwrap()].connect("/");
+    pub fn timeout(mut self, timeout: Duration) -> DeleteEvent {
+        self.request.timeout(timeout);
+        self
+    }
 
-        if ref_.is_some() {
-            client.header("If-Match", ref_.unwrap().as_slice());
-        }
+    pub fn exec(self) -> Result<bool, OrchestrateError> {
+        let (client, request) = try!(self.prepare());
+        decode_delete(try!(client.exec(&request)))
+    }
 
-        let mut res = try!(client.trailing(url.as_slice())
-                                 .method(Delete)
-                                 .exec());
+    pub fn exec_async(self) -> OrchestrateFuture<bool> {
+        let (client, request) = match self.prepare() {
+            Ok(prepared) => prepared,
+            Err(err) => return Future::from_value(Err(err))
+        };
+        client.exec_async(request, proc(res) decode_delete(res))
+    }
 
-        if (res.status as i32) != 204 {
-            return Err(RequestError(try!(res.read_to_string())));
-        }
+    fn prepare(self) -> Result<(Arc<Client>, RequestBuilder), OrchestrateError> {
+        let DeleteEvent { client, url, mut request, timestamp, ordinal } = self;
+        let timestamp = try!(require(timestamp, "timestamp"));
+        let ordinal = try!(require(ordinal, "ordinal"));
+        request.trailing(vec![url, timestamp, ordinal].connect("/").as_slice());
+        Ok((client, request))
+    }
+}
 
-        Ok(true)
+fn decode_delete(res: Response) -> Result<bool, OrchestrateError> {
+    if (res.status as i32) != 204 {
+        return Err(unexpected_status(204, res.status));
     }
+
+    Ok(true)
 }
 
-pub struct GetEvents<'a> {
-    client: &'a mut Client,
-    url: String
+pub struct GetEvents {
+    client: Arc<Client>,
+    request: RequestBuilder
 }
 
-impl<'a> GetEvents<'a> {
-    pub fn new(client: &'a mut Client, collection: &str, key: &str, kind: &str)
-               -> GetEvents<'a> {
+impl GetEvents {
+    pub fn new(client: Arc<Client>, collection: &str, key: &str, kind: &str)
+               -> GetEvents {
         GetEvents {
             client: client,
-            url: format!("{}/{}/events/{}", collection, key, kind)
+            request: RequestBuilder::with_path(EventsList, collection,
+                                               &[Collection(collection), Key(key),
+                                                 Literal("events"), Kind(kind)])
         }
     }
 
-    pub fn start(mut self, start: u64) -> GetEvents<'a> {
-        self.client.query("start", start.to_string().as_slice());
+    pub fn start(mut self, start: u64) -> GetEvents {
+        self.request.query("start", start.to_string().as_slice());
+        self
+    }
+
+    pub fn end(mut self, end: u64) -> GetEvents {
+        self.request.query("end", end.to_string().as_slice());
         self
     }
 
-    pub fn end(mut self, end: u64) -> GetEvents<'a> {
-        self.client.query("end", end.to_string().as_slice());
+    pub fn limit(mut self, limit: int) -> GetEvents {
+        self.request.query("limit", limit.to_string().as_slice());
         self
     }
 
-    pub fn limit(mut self, limit: int) -> GetEvents<'a> {
-        self.client.query("limit", limit.to_string().as_slice());
+    pub fn start_event(mut self, start_event: u64) -> GetEvents {
+        self.request.query("startEvent", start_event.to_string().as_slice());
         self
     }
 
-    pub fn start_event(mut self, start_event: u64) -> GetEvents<'a> {
-        self.client.query("startEvent", start_event.to_string().as_slice());
+    pub fn after_event(mut self, after_event: u64) -> GetEvents {
+        self.request.query("afterEvent", after_event.to_string().as_slice());
         self
     }
 
-    pub fn after_event(mut self, after_event: u64) -> GetEvents<'a> {
-        self.client.query("afterEvent", after_event.to_string().as_slice());
+    pub fn before_event(mut self, before_event: u64) -> GetEvents {
+        self.request.query("beforeEvent", before_event.to_string().as_slice());
         self
     }
 
-    pub fn before_event(mut self, before_event: u64) -> GetEvents<'a> {
-        self.client.query("beforeEvent", before_event.to_string().as_slice());
+    pub fn end_event(mut self, end_event: u64) -> GetEvents {
+        self.request.query("endEvent", end_event.to_string().as_slice());
         self
     }
 
-    pub fn end_event(mut self, end_event: u64) -> GetEvents<'a> {
-        self.client.query("endEvent", end_event.to_string().as_slice());
+    pub fn timeout(mut self, timeout: Duration) -> GetEvents {
+        self.request.timeout(timeout);
         self
     }
 
     pub fn exec<T: RepresentsJSON>(self)
                 -> Result<EventResults<T>, OrchestrateError> {
-        let GetEvents { client, url } = self;
-        let mut res = try!(client.trailing(url.as_slice()).method(Get).exec());
-        let body = try!(res.read_to_string());
+        let GetEvents { client, request } = self;
+        decode_events(try!(client.exec(&request)))
+    }
 
-        if (res.status as i32) != 200 {
-            return Err(RequestError(body));
-        }
+    pub fn exec_async<T: RepresentsJSON + Send>(self)
+                      -> OrchestrateFuture<EventResults<T>> {
+        let GetEvents { client, request } = self;
+        client.exec_async(request, proc(res) decode_events(res))
+    }
 
-        Ok(try!(json::decode::<EventResults<T>>(body.as_slice())))
+    // yields the results one at a time as the body is read
+    pub fn exec_stream<T: RepresentsJSON>(self)
+                       -> Result<ResultStream<EventResult<T>>,
+                                 OrchestrateError> {
+        let GetEvents { client, request } = self;
+        ResultStream::from_response(try!(client.exec(&request)), 200)
     }
 }
+
+fn decode_events<T: RepresentsJSON>(mut res: Response)
+                                    -> Result<EventResults<T>, OrchestrateError> {
+    let body = try!(res.read_to_string());
+
+    if (res.status as i32) != 200 {
+        return Err(unexpected_status(200, res.status));
+    }
+
+    Ok(try!(json::decode::<EventResults<T>>(body.as_slice())))
+}
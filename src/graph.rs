@@ -1,10 +1,15 @@
-use client::Client;
+use client::{Client, RequestBuilder};
+use transport::Response;
 use path::Path;
 use error::OrchestrateError;
-use error::OrchestrateError::RequestError;
-use RepresentsJSON;
+use error::unexpected_status;
+use {RepresentsJSON, OrchestrateFuture};
+use stream::ResultStream;
+use std::time::Duration;
+use std::sync::Arc;
 use serialize::json;
-use hyper::method::{Get, Put, Delete};
+use operation::Operation::{RelationsGet, RelationsPut, RelationsDelete};
+use names::Segment::{Collection, Key, Kind, Literal};
 
 #[deriving(Decodable, Encodable, Show)]
 pub struct GraphResults<T> {
@@ -18,95 +23,170 @@ pub struct GraphResult<T> {
     pub value: T
 }
 
-pub struct GetRelations<'a> {
-    client: &'a mut Client,
-    url: String
+pub struct GetRelations {
+    client: Arc<Client>,
+    request: RequestBuilder
 }
 
-impl<'a> GetRelations<'a> {
-    pub fn new(client: &'a mut Client, collection: &str, key: &str,
-               hops: Vec<&str>) -> GetRelations<'a> {
-        let relations_path = hops.connect("/");
+impl GetRelations {
+    pub fn new(client: Arc<Client>, collection: &str, key: &str,
+               hops: Vec<&str>) -> GetRelations {
+        let mut segments = vec![Collection(collection), Key(key),
+                                Literal("relations")];
+        segments.extend(hops.iter().map(|hop| Kind(*hop)));
+        let mut request = RequestBuilder::with_path(RelationsGet, collection,
+                                                    segments.as_slice());
+        if hops.is_empty() {
+            request.invalid = Some("at least one relation kind is required"
+                                   .to_string());
+        }
         GetRelations {
             client: client,
-            url: format!("{}/{}/relations/{}", collection, key, relations_path)
+            request: request
         }
     }
 
-    pub fn limit(mut self, limit: int) -> GetRelations<'a> {
-        self.client.query("limit", limit.to_string().as_slice());
+    pub fn limit(mut self, limit: int) -> GetRelations {
+        self.request.query("limit", limit.to_string().as_slice());
         self
     }
 
-    pub fn offset(mut self, offset: int) -> GetRelations<'a> {
-        self.client.query("offset", offset.to_string().as_slice());
+    pub fn offset(mut self, offset: int) -> GetRelations {
+        self.request.query("offset", offset.to_string().as_slice());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> GetRelations {
+        self.request.timeout(timeout);
         self
     }
 
     pub fn exec<T: RepresentsJSON>(self)
                 -> Result<GraphResults<T>, OrchestrateError> {
-        let mut res = try!(self.client.trailing(self.url.as_slice())
-                                      .method(Get).exec());
-        let body = try!(res.read_to_string());
+        decode_relations(try!(self.client.exec(&self.request)))
+    }
 
-        if (res.status as i32) != 200 {
-            return Err(RequestError(body));
-        }
+    pub fn exec_async<T: RepresentsJSON + Send>(self)
+                      -> OrchestrateFuture<GraphResults<T>> {
+        let GetRelations { client, request } = self;
+        client.exec_async(request, proc(res) decode_relations(res))
+    }
 
-        Ok(try!(json::decode::<GraphResults<T>>(body.as_slice())))
+    // yields the results one at a time as the body is read
+    pub fn exec_stream<T: RepresentsJSON>(self)
+                       -> Result<ResultStream<GraphResult<T>>, OrchestrateError> {
+        ResultStream::from_response(try!(self.client.exec(&self.request)), 200)
     }
 }
 
-pub struct PutRelation<'a> {
-    client: &'a mut Client,
-    url: String
+fn decode_relations<T: RepresentsJSON>(mut res: Response)
+                                       -> Result<GraphResults<T>, OrchestrateError> {
+    let body = try!(res.read_to_string());
+
+    if (res.status as i32) != 200 {
+        return Err(unexpected_status(200, res.status));
+    }
+
+    Ok(try!(json::decode::<GraphResults<T>>(body.as_slice())))
+}
+
+pub struct PutRelation {
+    client: Arc<Client>,
+    request: RequestBuilder
 }
 
-impl<'a> PutRelation<'a> {
-    pub fn new(client: &'a mut Client, collection: &str, key: &str, kind: &str,
-               to_collection: &str, to_key: &str) -> PutRelation<'a> {
-        PutRelation {
+impl PutRelation {
+    pub fn new(client: Arc<Client>, collection: &str, key: &str, kind: &str,
+               to_collection: &str, to_key: &str) -> PutRelation {
+        let mut relation = PutRelation {
             client: client,
-            url: format!("{}/{}/relation/{}/{}/{}", collection, key, kind,
-                         to_collection, to_key)
-        }
+            request: RequestBuilder::with_path(RelationsPut, collection,
+                                               &[Collection(collection), Key(key),
+                                                 Literal("relation"), Kind(kind),
+                                                 Collection(to_collection),
+                                                 Key(to_key)])
+        };
+        // relating the same two items twice is harmless
+        relation.request.retryable(true);
+        relation
     }
 
-    pub fn exec(self) -> Result<bool, OrchestrateError> {
-        let mut res = try!(self.client.trailing(self.url.as_slice())
-                                      .method(Put).exec());
+    pub fn timeout(mut self, timeout: Duration) -> PutRelation {
+        self.request.timeout(timeout);
+        self
+    }
 
-        if (res.status as i32) != 204 {
-            return Err(RequestError(try!(res.read_to_string())));
-        }
+    pub fn exec(self) -> Result<bool, OrchestrateError> {
+        decode_no_content(try!(self.client.exec(&self.request)))
+    }
 
-        Ok(true)
+    pub fn exec_async(self) -> OrchestrateFuture<bool> {
+        let PutRelation { client, request } = self;
+        client.exec_async(request, proc(res) decode_no_content(res))
     }
 }
 
-pub struct DeleteRelation<'a> {
-    client: &'a mut Client,
-    url: String
+pub struct DeleteRelation {
+    client: Arc<Client>,
+    request: RequestBuilder
 }
 
-impl<'a> DeleteRelation<'a> {
-    pub fn new(client: &'a mut Client, collection: &str, key: &str, kind: &str,
-               to_collection: &str, to_key: &str) -> DeleteRelation<'a> {
+impl DeleteRelation {
+    pub fn new(client: Arc<Client>, collection: &str, key: &str, kind: &str,
+               to_collection: &str, to_key: &str) -> DeleteRelation {
         DeleteRelation {
             client: client,
-            url: format!("{}/{}/relation/{}/{}/{}", collection, key, kind,
-                         to_collection, to_key)
+            request: RequestBuilder::with_path(RelationsDelete, collection,
+                                               &[Collection(collection), Key(key),
+                                                 Literal("relation"), Kind(kind),
+                                                 Collection(to_collection),
+                                                 Key(to_key)])
         }
     }
 
+    pub fn timeout(mut self, timeout: Duration) -> DeleteRelation {
+        self.request.timeout(timeout);
+        self
+    }
+
     pub fn exec(self) -> Result<bool, OrchestrateError> {
-        let mut res = try!(self.client.trailing(self.url.as_slice())
-                                      .method(Delete).exec());
+        decode_no_content(try!(self.client.exec(&self.request)))
+    }
 
-        if (res.status as i32) != 204 {
-            return Err(RequestError(try!(res.read_to_string())));
-        }
+    pub fn exec_async(self) -> OrchestrateFuture<bool> {
+        let DeleteRelation { client, request } = self;
+        client.exec_async(request, proc(res) decode_no_content(res))
+    }
+}
+
+fn decode_no_content(res: Response) -> Result<bool, OrchestrateError> {
+    if (res.status as i32) != 204 {
+        return Err(unexpected_status(204, res.status));
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use Orchestrate;
+    use error::ErrorKind;
+    use transport::MemoryTransport;
+
+    #[deriving(Decodable, Show)]
+    struct User {
+        name: String
+    }
+
+    #[test]
+    fn relations_need_a_kind() {
+        let transport = MemoryTransport::new();
+        let mut client = Orchestrate::new("key");
+        client.set_transport(transport.clone());
 
-        Ok(true)
+        let err = client.get_relations("users", "bob", vec![])
+                        .exec::<User>().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Validation);
+        assert!(transport.requests().is_empty());
     }
 }
@@ -0,0 +1,55 @@
+use std::sync::{Arc, Mutex};
+
+// An ordered list of hosts serving the same data; requests go to the first
+// one currently considered healthy.
+#[deriving(Clone)]
+pub struct Hosts {
+    hosts: Vec<String>,
+    healthy: Arc<Mutex<Vec<bool>>>
+}
+
+impl Hosts {
+
+    // every host is presumed healthy until a check says otherwise
+    pub fn new(hosts: Vec<String>) -> Hosts {
+        let healthy = Vec::from_elem(hosts.len(), true);
+        Hosts {
+            hosts: hosts,
+            healthy: Arc::new(Mutex::new(healthy))
+        }
+    }
+
+    pub fn all(&self) -> &[String] {
+        self.hosts.as_slice()
+    }
+
+    // falls back to the first host when none is healthy, so requests still
+    // have somewhere to go
+    pub fn active(&self) -> String {
+        let healthy = self.healthy.lock();
+        range(0, self.hosts.len()).find(|&i| healthy[i])
+                                  .map_or(self.hosts[0].clone(),
+                                          |i| self.hosts[i].clone())
+    }
+
+    pub fn is_healthy(&self, host: &str) -> bool {
+        let healthy = self.healthy.lock();
+        self.hosts.iter().position(|h| h.as_slice() == host)
+                         .map_or(false, |i| healthy[i])
+    }
+
+    pub fn mark_all(&self, up: bool) {
+        let mut healthy = self.healthy.lock();
+        for h in healthy.iter_mut() {
+            *h = up;
+        }
+    }
+
+    pub fn mark(&self, host: &str, up: bool) {
+        let mut healthy = self.healthy.lock();
+        match self.hosts.iter().position(|h| h.as_slice() == host) {
+            Some(i) => healthy[i] = up,
+            None => {}
+        }
+    }
+}
@@ -1,18 +1,27 @@
-use client::Client;
+use client::{Client, RequestBuilder};
+use transport::Response;
 use path::Path;
 use error::OrchestrateError;
-use error::OrchestrateError::RequestError;
-use RepresentsJSON;
+use error::OrchestrateError::Protocol;
+use error::{require, unexpected_status};
+use {RepresentsJSON, OrchestrateFuture};
+use stream::ResultStream;
+use std::time::Duration;
+use std::sync::{Arc, Future};
 use serialize::{json, Encodable};
 use serialize::json::Encoder;
 use std::io::IoError;
 use hyper::header::common::location::Location;
-use hyper::method::{Get, Put, Post, Delete};
+use operation::Operation::{KvGet, KvCreate, KvUpdate, KvDelete, KvList};
+use names::Segment::{Collection, Key, Literal, Ref};
+use time;
 
 #[deriving(Encodable, Decodable, Show)]
 pub struct KeyValueResult<T> {
     pub path: Path,
-    pub value: T
+    pub value: T,
+    // milliseconds since the epoch
+    pub reftime: Option<u64>
 }
 
 #[deriving(Encodable, Decodable, Show)]
@@ -22,62 +31,123 @@ pub struct KeyValueResults<T> {
     pub next: Option<String>
 }
 
-pub struct GetKeyValue<'a> {
-    client: &'a mut Client,
+pub struct GetKeyValue {
+    client: Arc<Client>,
     collection: String,
     key: String,
-    url: String
+    ref_: Option<String>,
+    request: RequestBuilder
 }
 
-impl<'a> GetKeyValue<'a> {
+impl GetKeyValue {
 
-    pub fn new(client: &'a mut Client, collection: &str, key: &str)
-               -> GetKeyValue<'a> {
+    pub fn new(client: Arc<Client>, collection: &str, key: &str)
+               -> GetKeyValue {
         GetKeyValue {
             client: client,
             collection: collection.to_string(),
             key: key.to_string(),
-            url: format!("{}/{}", collection, key)
+            ref_: None,
+            request: RequestBuilder::with_path(KvGet, collection,
+                                               &[Collection(collection), Key(key)])
         }
     }
 
+    // the value as it was at `ref_` rather than the current one
+    pub fn at_ref(mut self, ref_: &str) -> GetKeyValue {
+        self.request.segments(&[Collection(self.collection.as_slice()),
+                                Key(self.key.as_slice()),
+                                Literal("refs"), Ref(ref_)]);
+        self.ref_ = Some(ref_.to_string());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> GetKeyValue {
+        self.request.timeout(timeout);
+        self
+    }
+
     pub fn exec<T: RepresentsJSON>(self)
                 -> Result<KeyValueResult<T>, OrchestrateError> {
-        let GetKeyValue { client, collection, key, url } = self;
-        let mut res = try!(client.trailing(url.as_slice())
-                                 .method(Get)
-                                 .exec());
-        let body = try!(res.read_to_string());
-
-        if (res.status as i32) != 200 {
-            return Err(RequestError(body));
-        }
+        let GetKeyValue { client, key, ref_, request, .. } = self;
+        let (collection, res) = try!(client.exec_in(&request));
+        decode_get(collection, key, ref_, res)
+    }
 
-        Ok(KeyValueResult {
-            path: Path {
-                collection: collection,
-                key: key,
-                ref_: None
-            },
-            value: try!(json::decode::<T>(body.as_slice()))
+    pub fn exec_async<T: RepresentsJSON + Send>(self)
+                      -> OrchestrateFuture<KeyValueResult<T>> {
+        let GetKeyValue { client, key, ref_, request, .. } = self;
+        client.exec_async_in(request, proc(collection, res) {
+            decode_get(collection, key, ref_, res)
         })
     }
 }
 
-pub struct CreateKeyValue<'a> {
-    client: &'a mut Client,
-    collection: String,
-    url: String,
+fn decode_get<T: RepresentsJSON>(collection: String, key: String,
+                                 ref_: Option<String>, mut res: Response)
+                                 -> Result<KeyValueResult<T>, OrchestrateError> {
+    let body = try!(res.read_to_string());
+
+    if (res.status as i32) != 200 {
+        return Err(unexpected_status(200, res.status));
+    }
+
+    Ok(KeyValueResult {
+        path: Path {
+            collection: collection,
+            key: key,
+            ref_: header(&res, "ETag").and_then(|etag| parse_etag(etag.as_slice()))
+                                      .or(ref_)
+        },
+        value: try!(json::decode::<T>(body.as_slice())),
+        reftime: header(&res, "Last-Modified").and_then(|date| {
+            parse_http_date(date.as_slice())
+        })
+    })
+}
+
+fn header(res: &Response, name: &str) -> Option<String> {
+    res.headers.get_raw(name)
+               .and_then(|values| values.head())
+               .and_then(|value| String::from_utf8(value.clone()).ok())
+}
+
+// the ref is the entity tag, which may be weak or carry a -gzip suffix when
+// the body was compressed
+fn parse_etag(etag: &str) -> Option<String> {
+    let etag = etag.trim();
+    let etag = if etag.starts_with("W/") { etag.slice_from(2) } else { etag };
+    let etag = etag.trim_chars('"');
+    let etag = if etag.ends_with("-gzip") {
+        etag.slice_to(etag.len() - 5)
+    } else {
+        etag
+    };
+
+    if etag.is_empty() { None } else { Some(etag.to_string()) }
+}
+
+// milliseconds since the epoch of an RFC 1123 date
+fn parse_http_date(date: &str) -> Option<u64> {
+    time::strptime(date.trim(), "%a, %d %b %Y %H:%M:%S GMT").ok().map(|tm| {
+        let spec = tm.to_timespec();
+        spec.sec as u64 * 1000 + spec.nsec as u64 / 1_000_000
+    })
+}
+
+pub struct CreateKeyValue {
+    client: Arc<Client>,
+    request: RequestBuilder,
     data: Option<String>
 }
 
-impl<'a> CreateKeyValue<'a> {
+impl CreateKeyValue {
 
-    pub fn new(client: &'a mut Client, collection: &str) -> CreateKeyValue<'a> {
+    pub fn new(client: Arc<Client>, collection: &str) -> CreateKeyValue {
         CreateKeyValue {
             client: client,
-            collection: collection.to_string(),
-            url: collection.to_string(),
+            request: RequestBuilder::with_path(KvCreate, collection,
+                                               &[Collection(collection)]),
             data: None
         }
     }
@@ -85,206 +155,356 @@ impl<'a> CreateKeyValue<'a> {
     pub fn data<'b,
                 T: Encodable<Encoder<'b>, IoError>>(
                     mut self,
-                    data: &T) -> CreateKeyValue<'a> {
+                    data: &T) -> CreateKeyValue {
         self.data = Some(json::encode(&data));
         self
     }
 
-    pub fn exec(self) -> Result<Path, OrchestrateError> {
-        let CreateKeyValue { client, collection, url, data } = self;
-        let mut res = try!(client.trailing(url.as_slice())
-                                 .body(data.unwrap().as_slice())
-                                 .method(Post)
-                                 .exec());
-        let body = try!(res.read_to_string());
-
-        if (res.status as i32) != 201 {
-            return Err(RequestError(body));
-        }
+    pub fn retryable(mut self) -> CreateKeyValue {
+        self.request.retryable(true);
+        self
+    }
 
-        let Location(ref location) = *res.headers.get::<Location>().unwrap();
-        let parts: Vec<&str> = location.split('/').collect();
+    pub fn timeout(mut self, timeout: Duration) -> CreateKeyValue {
+        self.request.timeout(timeout);
+        self
+    }
 
-        Ok(Path {
-            collection: collection,
-            key: parts[3].to_string(),
-            ref_: Some(parts[5].to_string())
+    pub fn exec(self) -> Result<Path, OrchestrateError> {
+        let CreateKeyValue { client, mut request, data } = self;
+        request.body(try!(require(data, "data")).as_slice());
+        let (collection, res) = try!(client.exec_in(&request));
+        decode_create(collection, res)
+    }
+
+    pub fn exec_async(self) -> OrchestrateFuture<Path> {
+        let CreateKeyValue { client, mut request, data } = self;
+        match require(data, "data") {
+            Ok(data) => request.body(data.as_slice()),
+            Err(err) => return Future::from_value(Err(err))
+        };
+        client.exec_async_in(request, proc(collection, res) {
+            decode_create(collection, res)
         })
     }
 }
 
-pub struct UpdateKeyValue<'a> {
-    client: &'a mut Client,
-    collection: String,
+fn decode_create(collection: String, mut res: Response)
+                 -> Result<Path, OrchestrateError> {
+    try!(res.read_to_end());
+
+    if (res.status as i32) != 201 {
+        return Err(unexpected_status(201, res.status));
+    }
+
+    let (key, ref_) = try!(parse_location(&res));
+    Ok(Path {
+        collection: collection,
+        key: key,
+        ref_: Some(ref_)
+    })
+}
+
+// the Location of a written item ends with {key}/refs/{ref}
+fn parse_location(res: &Response) -> Result<(String, String), OrchestrateError> {
+    let location = match res.headers.get::<Location>() {
+        Some(&Location(ref location)) => location.clone(),
+        None => return Err(Protocol("no Location header in response".to_string()))
+    };
+
+    let parts: Vec<&str> = location.as_slice()
+                                   .split('?').next().unwrap_or("")
+                                   .split('/')
+                                   .filter(|part| !part.is_empty())
+                                   .collect();
+    let n = parts.len();
+    if n < 3 || parts[n - 2] != "refs" {
+        return Err(Protocol(format!("unexpected Location header {}", location)));
+    }
+    Ok((parts[n - 3].to_string(), parts[n - 1].to_string()))
+}
+
+pub struct UpdateKeyValue {
+    client: Arc<Client>,
     key: String,
-    url: String,
-    data: Option<String>,
-    ref_: Option<String>,
-    if_absent: Option<bool>
+    request: RequestBuilder,
+    data: Option<String>
 }
 
-impl<'a> UpdateKeyValue<'a> {
+impl UpdateKeyValue {
 
-    pub fn new(client: &'a mut Client, collection: &str, key: &str)
-               -> UpdateKeyValue<'a> {
+    pub fn new(client: Arc<Client>, collection: &str, key: &str)
+               -> UpdateKeyValue {
         UpdateKeyValue {
             client: client,
-            collection: collection.to_string(),
             key: key.to_string(),
-            url: format!("{}/{}", collection.to_string(), key.to_string()),
-            data: None,
-            ref_: None,
-            if_absent: None
+            request: RequestBuilder::with_path(KvUpdate, collection,
+                                               &[Collection(collection), Key(key)]),
+            data: None
         }
     }
 
     pub fn data<'b,
                 T: Encodable<Encoder<'b>, IoError>>(
                     mut self,
-                    data: &T) -> UpdateKeyValue<'a> {
+                    data: &T) -> UpdateKeyValue {
         self.data = Some(json::encode(&data));
         self
     }
 
-    pub fn if_match(mut self, ref_: &str) -> UpdateKeyValue<'a> {
-        self.ref_ = Some(ref_.to_string());
+    pub fn if_match(mut self, ref_: &str) -> UpdateKeyValue {
+        self.request.header("If-Match", ref_);
         self
     }
 
-    pub fn if_absent(mut self) -> UpdateKeyValue<'a> {
-        self.if_absent = Some(true);
+    pub fn if_absent(mut self) -> UpdateKeyValue {
+        self.request.header("If-None-Match", "*");
         self
     }
 
-    pub fn exec(self) -> Result<Path, OrchestrateError> {
-        let UpdateKeyValue {
-          client, collection, key, url, data, ref_, if_absent
-        } = self;
-
-        let mut client = client.trailing(url.as_slice())
-                               .body(data.unwrap().as_slice())
-                               .method(Put);
-
-        if ref_.is_some() {
-            client.header("If-Match", ref_.unwrap().as_slice());
-        }
-
-        if if_absent.is_some() {
-            client.header("If-None-Match", "*");
-        }
+    pub fn timeout(mut self, timeout: Duration) -> UpdateKeyValue {
+        self.request.timeout(timeout);
+        self
+    }
 
-        let mut res = try!(client.exec());
-        let body = try!(res.read_to_string());
+    pub fn exec(self) -> Result<Path, OrchestrateError> {
+        let UpdateKeyValue { client, key, mut request, data } = self;
+        request.body(try!(require(data, "data")).as_slice());
+        let (collection, res) = try!(client.exec_in(&request));
+        decode_update(collection, key, res)
+    }
 
-        if (res.status as i32) != 201 {
-            return Err(RequestError(body));
-        }
+    pub fn exec_async(self) -> OrchestrateFuture<Path> {
+        let UpdateKeyValue { client, key, mut request, data } = self;
+        match require(data, "data") {
+            Ok(data) => request.body(data.as_slice()),
+            Err(err) => return Future::from_value(Err(err))
+        };
+        client.exec_async_in(request, proc(collection, res) {
+            decode_update(collection, key, res)
+        })
+    }
+}
 
-        let Location(ref location) = *res.headers.get::<Location>().unwrap();
-        let parts: Vec<&str> = location.split('/').collect();
+fn decode_update(collection: String, key: String, mut res: Response)
+                 -> Result<Path, OrchestrateError> {
+    try!(res.read_to_end());
 
-        Ok(Path {
-            collection: collection,
-            key: key,
-            ref_: Some(parts[5].to_string())
-        })
+    if (res.status as i32) != 201 {
+        return Err(unexpected_status(201, res.status));
     }
+
+    let (_, ref_) = try!(parse_location(&res));
+    Ok(Path {
+        collection: collection,
+        key: key,
+        ref_: Some(ref_)
+    })
 }
 
-pub struct DeleteKeyValue<'a> {
-    client: &'a mut Client,
-    ref_: Option<String>,
-    url: String
+pub struct DeleteKeyValue {
+    client: Arc<Client>,
+    request: RequestBuilder
 }
 
-impl<'a> DeleteKeyValue<'a> {
+impl DeleteKeyValue {
 
-    pub fn new(client: &'a mut Client, collection: &str, key: &str)
-               -> DeleteKeyValue<'a> {
+    pub fn new(client: Arc<Client>, collection: &str, key: &str)
+               -> DeleteKeyValue {
         DeleteKeyValue {
             client: client,
-            ref_: None,
-            url: format!("{}/{}", collection, key)
+            request: RequestBuilder::with_path(KvDelete, collection,
+                                               &[Collection(collection), Key(key)])
         }
     }
 
-    pub fn if_match(mut self, ref_: &str) -> DeleteKeyValue<'a> {
-        self.ref_ = Some(ref_.to_string());
+    pub fn if_match(mut self, ref_: &str) -> DeleteKeyValue {
+        self.request.header("If-Match", ref_);
         self
     }
 
-    pub fn purge(mut self) -> DeleteKeyValue<'a> {
-        self.client.query("purge", "true");
+    pub fn purge(mut self) -> DeleteKeyValue {
+        self.request.query("purge", "true");
         self
     }
 
-    pub fn exec(self) -> Result<bool, OrchestrateError> {
-        let DeleteKeyValue { client, ref_, url } = self;
-        let mut client = client.trailing(url.as_slice()).method(Delete);
-
-        if ref_.is_some() {
-            client.header("If-Match", ref_.unwrap().as_slice());
-        }
+    pub fn timeout(mut self, timeout: Duration) -> DeleteKeyValue {
+        self.request.timeout(timeout);
+        self
+    }
 
-        let mut res = try!(client.exec());
+    pub fn exec(self) -> Result<bool, OrchestrateError> {
+        let DeleteKeyValue { client, request } = self;
+        decode_delete(try!(client.exec(&request)))
+    }
 
-        if (res.status as i32) != 204 {
-            return Err(RequestError(try!(res.read_to_string())));
-        }
+    pub fn exec_async(self) -> OrchestrateFuture<bool> {
+        let DeleteKeyValue { client, request } = self;
+        client.exec_async(request, proc(res) decode_delete(res))
+    }
+}
 
-        Ok(true)
+fn decode_delete(res: Response) -> Result<bool, OrchestrateError> {
+    if (res.status as i32) != 204 {
+        return Err(unexpected_status(204, res.status));
     }
+
+    Ok(true)
 }
 
-pub struct ListReader<'a> {
-    collection: String,
-    client: &'a mut Client
+pub struct ListReader {
+    client: Arc<Client>,
+    request: RequestBuilder
 }
 
-impl<'a> ListReader<'a> {
-    pub fn new<'a>(client: &'a mut Client, collection: &str) -> ListReader<'a> {
+impl ListReader {
+    pub fn new(client: Arc<Client>, collection: &str) -> ListReader {
         ListReader {
-            collection: collection.to_string(),
-            client: client
+            client: client,
+            request: RequestBuilder::with_path(KvList, collection,
+                                               &[Collection(collection)])
         }
     }
 
-    pub fn limit(mut self, limit: int) -> ListReader<'a> {
-        self.client.query("limit", limit.to_string().as_slice());
+    pub fn limit(mut self, limit: int) -> ListReader {
+        self.request.query("limit", limit.to_string().as_slice());
+        self
+    }
+
+    pub fn start_key(mut self, start_key: &str) -> ListReader {
+        self.request.query("startKey", start_key);
         self
     }
 
-    pub fn start_key(mut self, start_key: &str) -> ListReader<'a> {
-        self.client.query("startKey", start_key);
+    pub fn after_key(mut self, after_key: &str) -> ListReader {
+        self.request.query("afterKey", after_key);
         self
     }
 
-    pub fn after_key(mut self, after_key: &str) -> ListReader<'a> {
-        self.client.query("afterKey", after_key);
+    pub fn before_key(mut self, before_key: &str) -> ListReader {
+        self.request.query("beforeKey", before_key);
         self
     }
 
-    pub fn before_key(mut self, before_key: &str) -> ListReader<'a> {
-        self.client.query("beforeKey", before_key);
+    pub fn end_key(mut self, end_key: &str) -> ListReader {
+        self.request.query("endKey", end_key);
         self
     }
 
-    pub fn end_key(mut self, end_key: &str) -> ListReader<'a> {
-        self.client.query("endKey", end_key);
+    pub fn timeout(mut self, timeout: Duration) -> ListReader {
+        self.request.timeout(timeout);
         self
     }
 
     pub fn exec<T: RepresentsJSON>(self)
                 -> Result<KeyValueResults<T>, OrchestrateError> {
-        let ListReader { client, collection } = self;
-        let mut res = try!(client.trailing(collection.as_slice())
-                                 .method(Get).exec());
-        let body = try!(res.read_to_string());
+        let ListReader { client, request } = self;
+        decode_list(try!(client.exec(&request)))
+    }
+
+    pub fn exec_async<T: RepresentsJSON + Send>(self)
+                      -> OrchestrateFuture<KeyValueResults<T>> {
+        let ListReader { client, request } = self;
+        client.exec_async(request, proc(res) decode_list(res))
+    }
+
+    // yields the results one at a time as the body is read
+    pub fn exec_stream<T: RepresentsJSON>(self)
+                       -> Result<ResultStream<KeyValueResult<T>>,
+                                 OrchestrateError> {
+        let ListReader { client, request } = self;
+        ResultStream::from_response(try!(client.exec(&request)), 200)
+    }
+}
+
+fn decode_list<T: RepresentsJSON>(mut res: Response)
+                                  -> Result<KeyValueResults<T>, OrchestrateError> {
+    let body = try!(res.read_to_string());
+
+    if (res.status as i32) != 200 {
+        return Err(unexpected_status(200, res.status));
+    }
+
+    Ok(try!(json::decode::<KeyValueResults<T>>(body.as_slice())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_etag, parse_location};
+    use Orchestrate;
+    use transport::{Response, MemoryTransport};
+    use hyper::header::Headers;
+    use hyper::header::common::location::Location;
+    use hyper::status::StatusCode::Created;
+    use std::io::MemReader;
+
+    #[deriving(Decodable, Show)]
+    struct User {
+        name: String
+    }
 
-        if (res.status as i32) != 200 {
-            return Err(RequestError(body));
+    fn created(location: Option<&str>) -> Response {
+        let mut headers = Headers::new();
+        match location {
+            Some(location) => headers.set(Location(location.to_string())),
+            None => {}
         }
+        Response::new(Created, headers, box MemReader::new(Vec::new()))
+    }
+
+    #[test]
+    fn parse_etag_strips_quotes_and_suffixes() {
+        let ref_ = Some("82eafab14dc84ed3".to_string());
+        assert_eq!(parse_etag("\"82eafab14dc84ed3\""), ref_);
+        assert_eq!(parse_etag("W/\"82eafab14dc84ed3\""), ref_);
+        assert_eq!(parse_etag("\"82eafab14dc84ed3-gzip\""), ref_);
+        assert_eq!(parse_etag("\"\""), None);
+    }
+
+    #[test]
+    fn get_captures_the_ref() {
+        let transport = MemoryTransport::new();
+        transport.respond_with_headers(200, vec![("ETag", "\"abc\"")],
+                                       "{\"name\": \"bob\"}");
+        let mut client = Orchestrate::new("key");
+        client.set_transport(transport.clone());
+
+        let result = client.get("users", "bob").exec::<User>().unwrap();
+        assert_eq!(result.value.name.as_slice(), "bob");
+        assert_eq!(result.path.ref_, Some("abc".to_string()));
+    }
+
+    #[test]
+    fn get_at_ref_requests_the_ref() {
+        let transport = MemoryTransport::new();
+        transport.respond(200, "{\"name\": \"bob\"}");
+        let mut client = Orchestrate::new("key");
+        client.set_transport(transport.clone());
+
+        let result = client.get("users", "bob").at_ref("abc")
+                           .exec::<User>().unwrap();
+        assert_eq!(result.path.ref_, Some("abc".to_string()));
+        assert_eq!(transport.requests()[0].url.to_string().as_slice(),
+                   "https://api.orchestrate.io/v0/users/bob/refs/abc");
+    }
+
+    #[test]
+    fn parse_location_reads_key_and_ref() {
+        let res = created(Some("/v0/users/bob/refs/82eafab14dc84ed3"));
+        assert_eq!(parse_location(&res).unwrap(),
+                   ("bob".to_string(), "82eafab14dc84ed3".to_string()));
+    }
+
+    #[test]
+    fn parse_location_ignores_the_query() {
+        let res = created(Some("/v0/users/bob/refs/abc?x=1"));
+        assert_eq!(parse_location(&res).unwrap(),
+                   ("bob".to_string(), "abc".to_string()));
+    }
 
-        Ok(try!(json::decode::<KeyValueResults<T>>(body.as_slice())))
+    #[test]
+    fn parse_location_rejects_other_paths() {
+        assert!(parse_location(&created(Some("/v0/users/bob"))).is_err());
+        assert!(parse_location(&created(None)).is_err());
     }
 }
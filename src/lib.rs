@@ -5,25 +5,49 @@
 extern crate serialize;
 extern crate hyper;
 extern crate url;
+extern crate time;
+extern crate openssl;
+extern crate flate2;
 
-pub use error::OrchestrateError;
-use error::OrchestrateError::RequestError;
+pub use error::{OrchestrateError, ApiError, ErrorKind};
+use error::OrchestrateError::Validation;
+use error::unexpected_status;
 
-use client::Client;
+pub use client::RequestBuilder;
+use client::{Client, Tasks};
 use key_value::{
     GetKeyValue, CreateKeyValue, UpdateKeyValue, DeleteKeyValue, ListReader,
 };
 use search::SearchBuilder;
 use events::{GetEvents, CreateEvent, DeleteEvent};
 use graph::{GetRelations, PutRelation, DeleteRelation};
+use transport::{Transport, HttpTransport, Response};
+use pool::Pool;
+use net::{Proxy, TlsConfig};
+use cassette::{Cassette, CassetteMode};
+use retry::RetryPolicy;
+use observer::Observer;
+use metrics::MetricsSink;
+use rate_limit::RateLimiter;
+use breaker::CircuitBreaker;
+use hosts::Hosts;
+use credentials::CredentialProvider;
+use middleware::Middleware;
+use hyper::Url;
 use serialize::{json, Decoder, Decodable};
-use hyper::method::{Head, Delete};
+use operation::Operation::{Ping, DeleteCollection};
+use names::Segment::Collection;
+use std::sync::{Arc, Future};
+use std::time::Duration;
+
+pub type OrchestrateFuture<T> = Future<Result<T, OrchestrateError>>;
 
 pub trait RepresentsJSON : Decodable<json::Decoder, json::DecoderError> {}
 impl<T: Decodable<json::Decoder, json::DecoderError>> RepresentsJSON for T {}
 
+#[deriving(Clone)]
 pub struct Orchestrate {
-    client: Client
+    client: Arc<Client>
 }
 
 impl Orchestrate {
@@ -31,101 +55,359 @@ impl Orchestrate {
     // create a new orchestrate client
     pub fn new(token: &str) -> Orchestrate {
         Orchestrate {
-            client: Client::new(token)
+            client: Arc::new(Client::new(token))
         }
     }
 
+    // where the API key of each request comes from, replacing the token given
+    // to `new`
+    pub fn set_credentials<P: CredentialProvider + Send + Sync>(&mut self,
+                                                                provider: P)
+                                                                -> &mut Orchestrate {
+        self.client.make_unique().credentials =
+            Arc::new(box provider as Box<CredentialProvider + Send + Sync>);
+        self.restart_health_check();
+        self
+    }
+
     pub fn set_host(&mut self, host: &str) -> &mut Orchestrate {
-        self.client.host = host.to_string();
+        self.client.make_unique().hosts = Hosts::new(vec![host.to_string()]);
+        self.restart_health_check();
         self
     }
 
-    pub fn ping(&mut self) -> Result<bool, OrchestrateError> {
-        let mut res = try!(self.client.trailing("").method(Head).exec());
+    // scheme, host, port and path prefix, API version included, of every
+    // request, e.g. "http://localhost:8080/orchestrate/v0"; replaces any
+    // hosts set before
+    pub fn set_base_url(&mut self, base: &str)
+                        -> Result<&mut Orchestrate, OrchestrateError> {
+        let url = match Url::parse(base) {
+            Ok(url) => url,
+            Err(err) => return Err(Validation(
+                format!("invalid base url {}: {}", base, err)))
+        };
 
-        if (res.status as i32) != 200 {
-            return Err(RequestError(try!(res.read_to_string())));
+        if url.scheme.as_slice() != "http" && url.scheme.as_slice() != "https" {
+            return Err(Validation(
+                format!("unsupported scheme in base url {}", base)));
         }
 
-        Ok(true)
+        let host = match url.serialize_host() {
+            Some(host) => host,
+            None => return Err(Validation(
+                format!("no host in base url {}", base)))
+        };
+
+        {
+            let client = self.client.make_unique();
+            client.scheme = url.scheme.clone();
+            client.hosts = Hosts::new(vec![host]);
+            client.port = url.port();
+            client.prefix = url.path().map_or(String::new(), |segments| {
+                segments.iter()
+                        .filter(|s| !s.is_empty())
+                        .map(|s| s.as_slice())
+                        .collect::<Vec<&str>>()
+                        .connect("/")
+            });
+        }
+        self.restart_health_check();
+        Ok(self)
     }
 
-    pub fn delete_collection(&mut self, collection: &str)
-                             -> Result<bool, OrchestrateError> {
-        let mut res = try!(self.client.trailing(collection)
-                                      .query("force", "true")
-                                      .method(Delete)
-                                      .exec());
+    // hosts in order of preference, requests go to the first healthy one
+    pub fn set_hosts(&mut self, hosts: Vec<&str>)
+                     -> Result<&mut Orchestrate, OrchestrateError> {
+        if hosts.is_empty() {
+            return Err(Validation("at least one host is required".to_string()));
+        }
+
+        self.client.make_unique().hosts =
+            Hosts::new(hosts.iter().map(|host| host.to_string()).collect());
+        self.restart_health_check();
+        Ok(self)
+    }
+
+    // pings every host each `interval` in the background, routing around
+    // those that fail; call it once the client is otherwise configured
+    pub fn set_health_check(&mut self, interval: Option<Duration>)
+                            -> &mut Orchestrate {
+        let client = self.client.make_unique();
+        client.health_check = None;
+        let check = interval.map(|interval| {
+            Arc::new(client.start_health_check(interval))
+        });
+        // nothing would bring a host marked down back up anymore
+        if check.is_none() {
+            client.hosts.mark_all(true);
+        }
+        client.health_check = check;
+        self
+    }
+
+    pub fn active_host(&self) -> String {
+        self.client.hosts.active()
+    }
+
+    fn restart_health_check(&mut self) {
+        let interval = self.client.health_check.as_ref()
+                                               .map(|check| check.interval);
+        if interval.is_some() {
+            self.set_health_check(interval);
+        }
+    }
+
+    pub fn set_transport<T: Transport + Send + Sync>(&mut self, transport: T)
+                                                     -> &mut Orchestrate {
+        {
+            let client = self.client.make_unique();
+            client.cassette = None;
+            client.transport =
+                Arc::new(box transport as Box<Transport + Send + Sync>);
+        }
+        // the checker pings through its own copy of the client
+        self.restart_health_check();
+        self
+    }
+
+    // in record mode every interaction made through the current transport is
+    // written to `path`; in replay mode requests are answered from `path` only.
+    // The cassette stays in place when the HTTP settings below change.
+    pub fn set_cassette(&mut self, path: Path, mode: CassetteMode)
+                        -> Result<&mut Orchestrate, OrchestrateError> {
+        let cassette = match mode {
+            CassetteMode::Record => {
+                Cassette::record(path, self.client.transport.clone())
+            },
+            CassetteMode::Replay => try!(Cassette::replay(path))
+        };
+        self.set_transport(cassette.clone());
+        self.client.make_unique().cassette = Some(cassette);
+        Ok(self)
+    }
+
+    // the settings below configure the built-in HTTP transport, and put it
+    // back in place of any transport set with `set_transport`, behind the
+    // cassette if there is one
+
+    // keep up to `max_idle` connections per host open for `idle_timeout`
+    pub fn set_connection_pool(&mut self, max_idle: uint, idle_timeout: Duration)
+                               -> &mut Orchestrate {
+        let http = self.client.http.clone().pool(Pool::new(max_idle,
+                                                           idle_timeout));
+        self.set_http(http)
+    }
+
+    // tunnel connections through an HTTP CONNECT proxy; `Proxy::from_env()`
+    // picks one up from HTTPS_PROXY and NO_PROXY
+    pub fn set_proxy(&mut self, proxy: Option<Proxy>) -> &mut Orchestrate {
+        let http = self.client.http.clone().proxy(proxy);
+        self.set_http(http)
+    }
+
+    pub fn set_tls(&mut self, tls: TlsConfig) -> &mut Orchestrate {
+        let http = self.client.http.clone().tls(tls);
+        self.set_http(http)
+    }
+
+    fn set_http(&mut self, http: HttpTransport) -> &mut Orchestrate {
+        self.client.make_unique().http = http.clone();
+        match self.client.cassette.clone() {
+            Some(cassette) => {
+                let http = Arc::new(box http as Box<Transport + Send + Sync>);
+                let cassette = cassette.wrap(http);
+                self.set_transport(cassette.clone());
+                self.client.make_unique().cassette = Some(cassette);
+                self
+            },
+            None => self.set_transport(http)
+        }
+    }
+
+    pub fn set_connect_timeout(&mut self, timeout: Duration) -> &mut Orchestrate {
+        self.client.make_unique().timeouts.connect = Some(timeout);
+        self.restart_health_check();
+        self
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Duration) -> &mut Orchestrate {
+        self.client.make_unique().timeouts.read = Some(timeout);
+        self.restart_health_check();
+        self
+    }
+
+    // the deadline for a whole operation, including any retries
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Orchestrate {
+        self.client.make_unique().timeouts.total = Some(timeout);
+        self.restart_health_check();
+        self
+    }
+
+    pub fn set_observer<O: Observer + Send + Sync>(&mut self, observer: O)
+                                                   -> &mut Orchestrate {
+        self.client.make_unique().observer =
+            Some(Arc::new(box observer as Box<Observer + Send + Sync>));
+        self
+    }
+
+    pub fn set_metrics<M: MetricsSink + Send + Sync>(&mut self, metrics: M)
+                                                     -> &mut Orchestrate {
+        self.client.make_unique().metrics =
+            Some(Arc::new(box metrics as Box<MetricsSink + Send + Sync>));
+        self
+    }
+
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>)
+                            -> &mut Orchestrate {
+        self.client.make_unique().rate_limiter = limiter;
+        self
+    }
+
+    pub fn set_circuit_breaker(&mut self, breaker: Option<CircuitBreaker>)
+                               -> &mut Orchestrate {
+        self.client.make_unique().breaker = breaker;
+        self
+    }
+
+    // every exec returns `OrchestrateError::DryRun` with the request it would
+    // have sent instead of contacting the server
+    pub fn set_dry_run(&mut self, dry_run: bool) -> &mut Orchestrate {
+        self.client.make_unique().dry_run = dry_run;
+        self
+    }
+
+    // gzip the documents sent by `put`, `post` and `create_event` once they
+    // are at least `min_size` bytes; responses are always decoded
+    pub fn set_gzip_threshold(&mut self, min_size: Option<uint>)
+                              -> &mut Orchestrate {
+        self.client.make_unique().gzip_threshold = min_size;
+        self
+    }
+
+    // appends to the chain run around every request
+    pub fn add_middleware<M: Middleware + Send + Sync>(&mut self, middleware: M)
+                                                       -> &mut Orchestrate {
+        self.client.make_unique().middleware.push(
+            Arc::new(box middleware as Box<Middleware + Send + Sync>));
+        self
+    }
 
-        if (res.status as i32) != 204 {
-            return Err(RequestError(try!(res.read_to_string())));
+    // how many operations run at once through `exec_async`, the rest wait
+    // their turn; 4 by default
+    pub fn set_async_threads(&mut self, threads: uint)
+                             -> Result<&mut Orchestrate, OrchestrateError> {
+        if threads == 0 {
+            return Err(Validation("at least one async thread is required"
+                                  .to_string()));
         }
+        self.client.make_unique().tasks = Tasks::new(threads);
+        Ok(self)
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) -> &mut Orchestrate {
+        self.client.make_unique().retry = policy;
+        self
+    }
 
-        Ok(true)
+    pub fn ping(&self) -> Result<bool, OrchestrateError> {
+        decode_ping(try!(self.client.exec(&RequestBuilder::new(Ping, "", ""))))
     }
 
-    pub fn get<'a>(&'a mut self, collection: &str, key: &str)
-                    -> GetKeyValue<'a> {
-        GetKeyValue::new(&mut self.client, collection, key)
+    pub fn ping_async(&self) -> OrchestrateFuture<bool> {
+        self.client.exec_async(RequestBuilder::new(Ping, "", ""),
+                               proc(res) decode_ping(res))
+    }
+
+    pub fn delete_collection(&self, collection: &str)
+                             -> Result<bool, OrchestrateError> {
+        let mut request = RequestBuilder::with_path(DeleteCollection, collection,
+                                                    &[Collection(collection)]);
+        request.query("force", "true");
+        decode_delete_collection(try!(self.client.exec(&request)))
     }
 
-    pub fn post<'a>(&'a mut self, collection: &str) -> CreateKeyValue<'a> {
-        CreateKeyValue::new(&mut self.client, collection)
+    pub fn delete_collection_async(&self, collection: &str)
+                                   -> OrchestrateFuture<bool> {
+        let mut request = RequestBuilder::with_path(DeleteCollection, collection,
+                                                    &[Collection(collection)]);
+        request.query("force", "true");
+        self.client.exec_async(request, proc(res) decode_delete_collection(res))
     }
 
-    pub fn put<'a>(&'a mut self, collection: &str, key: &str)
-                   -> UpdateKeyValue<'a> {
-        UpdateKeyValue::new(&mut self.client, collection, key)
+    pub fn get(&self, collection: &str, key: &str) -> GetKeyValue {
+        GetKeyValue::new(self.client.clone(), collection, key)
     }
 
-    pub fn delete<'a>(&'a mut self, collection: &str, key: &str)
-                      -> DeleteKeyValue<'a> {
-        DeleteKeyValue::new(&mut self.client, collection, key)
+    pub fn post(&self, collection: &str) -> CreateKeyValue {
+        CreateKeyValue::new(self.client.clone(), collection)
     }
 
-    pub fn list<'a>(&'a mut self, collection: &str) -> ListReader<'a> {
-        ListReader::new(&mut self.client, collection)
+    pub fn put(&self, collection: &str, key: &str) -> UpdateKeyValue {
+        UpdateKeyValue::new(self.client.clone(), collection, key)
     }
 
-    pub fn search<'a>(&'a mut self, collection: &str) -> SearchBuilder<'a> {
-        SearchBuilder::new(&mut self.client, collection)
+    pub fn delete(&self, collection: &str, key: &str) -> DeleteKeyValue {
+        DeleteKeyValue::new(self.client.clone(), collection, key)
     }
 
-    pub fn get_events<'a>(&'a mut self, collection: &str, key: &str, kind: &str)
-                          -> GetEvents<'a> {
-        GetEvents::new(&mut self.client, collection, key, kind)
+    pub fn list(&self, collection: &str) -> ListReader {
+        ListReader::new(self.client.clone(), collection)
     }
 
-    pub fn create_event<'a>(&'a mut self, collection: &str, key: &str,
-                            kind: &str) -> CreateEvent<'a> {
-        CreateEvent::new(&mut self.client, collection, key, kind)
+    pub fn search(&self, collection: &str) -> SearchBuilder {
+        SearchBuilder::new(self.client.clone(), collection)
     }
 
-    pub fn delete_event<'a>(&'a mut self, collection: &str, key: &str,
-                            kind: &str) -> DeleteEvent<'a> {
-        DeleteEvent::new(&mut self.client, collection, key, kind)
+    pub fn get_events(&self, collection: &str, key: &str, kind: &str)
+                      -> GetEvents {
+        GetEvents::new(self.client.clone(), collection, key, kind)
     }
 
-    pub fn get_relations<'a>(&'a mut self, collection: &str, key: &str,
-                             hops: Vec<&str>) -> GetRelations<'a> {
-        GetRelations::new(&mut self.client, collection, key, hops)
+    pub fn create_event(&self, collection: &str, key: &str, kind: &str)
+                        -> CreateEvent {
+        CreateEvent::new(self.client.clone(), collection, key, kind)
     }
 
-    pub fn put_relation<'a>(&'a mut self, collection: &str, key: &str,
-                            kind: &str, to_collection: &str, to_key: &str)
-                            -> PutRelation<'a> {
-        PutRelation::new(&mut self.client, collection, key, kind, to_collection,
-                         to_key)
+    pub fn delete_event(&self, collection: &str, key: &str, kind: &str)
+                        -> DeleteEvent {
+        DeleteEvent::new(self.client.clone(), collection, key, kind)
     }
 
-    pub fn delete_relation<'a>(&'a mut self, collection: &str, key: &str,
-                            kind: &str, to_collection: &str, to_key: &str)
-                            -> DeleteRelation<'a> {
-        DeleteRelation::new(&mut self.client, collection, key, kind,
+    pub fn get_relations(&self, collection: &str, key: &str,
+                         hops: Vec<&str>) -> GetRelations {
+        GetRelations::new(self.client.clone(), collection, key, hops)
+    }
+
+    pub fn put_relation(&self, collection: &str, key: &str, kind: &str,
+                        to_collection: &str, to_key: &str) -> PutRelation {
+        PutRelation::new(self.client.clone(), collection, key, kind,
+                         to_collection, to_key)
+    }
+
+    pub fn delete_relation(&self, collection: &str, key: &str, kind: &str,
+                           to_collection: &str, to_key: &str)
+                           -> DeleteRelation {
+        DeleteRelation::new(self.client.clone(), collection, key, kind,
                             to_collection, to_key)
     }
 }
 
+fn decode_ping(res: Response) -> Result<bool, OrchestrateError> {
+    if (res.status as i32) != 200 {
+        return Err(unexpected_status(200, res.status));
+    }
+
+    Ok(true)
+}
+
+fn decode_delete_collection(res: Response) -> Result<bool, OrchestrateError> {
+    if (res.status as i32) != 204 {
+        return Err(unexpected_status(204, res.status));
+    }
+
+    Ok(true)
+}
+
 mod client;
 mod error;
 mod path;
@@ -134,3 +416,94 @@ pub mod key_value;
 pub mod search;
 pub mod events;
 pub mod graph;
+pub mod transport;
+pub mod retry;
+pub mod pool;
+pub mod net;
+pub mod cassette;
+pub mod observer;
+pub mod operation;
+pub mod metrics;
+pub mod rate_limit;
+pub mod breaker;
+pub mod hosts;
+pub mod credentials;
+pub mod dry_run;
+pub mod middleware;
+pub mod stream;
+pub mod names;
+
+#[cfg(test)]
+mod tests {
+    use Orchestrate;
+    use hyper::method::{Get, Post};
+    use transport::MemoryTransport;
+
+    #[deriving(Encodable, Decodable, Show)]
+    struct User {
+        name: String
+    }
+
+    fn client(transport: &MemoryTransport) -> Orchestrate {
+        let mut client = Orchestrate::new("key");
+        client.set_transport(transport.clone());
+        client
+    }
+
+    #[test]
+    fn get_requests_the_key() {
+        let transport = MemoryTransport::new();
+        transport.respond(200, "{\"name\": \"bob\"}");
+
+        let result = client(&transport).get("users", "bob")
+                                       .exec::<User>().unwrap();
+        assert_eq!(result.value.name.as_slice(), "bob");
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, Get);
+        assert_eq!(requests[0].url.to_string().as_slice(),
+                   "https://api.orchestrate.io/v0/users/bob");
+    }
+
+    #[test]
+    fn post_sends_the_body() {
+        let transport = MemoryTransport::new();
+        transport.respond_with_headers(201, vec![("Location",
+                                                  "/v0/users/abc/refs/123")],
+                                       "");
+
+        let user = User { name: "bob".to_string() };
+        let path = client(&transport).post("users").data(&user).exec().unwrap();
+        assert_eq!(path.key.as_slice(), "abc");
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].method, Post);
+        assert_eq!(requests[0].body, Some("{\"name\":\"bob\"}".to_string()));
+        assert_eq!(requests[0].header("Content-Type"), Some("application/json"));
+    }
+
+    #[test]
+    fn list_sends_the_query() {
+        let transport = MemoryTransport::new();
+        transport.respond(200, "{\"count\": 0, \"results\": []}");
+
+        client(&transport).list("users").limit(10).start_key("b")
+                          .exec::<User>().unwrap();
+        let url = transport.requests()[0].url.clone();
+        assert_eq!(url.serialize_path().unwrap().as_slice(), "/v0/users");
+        assert_eq!(url.query, Some("limit=10&startKey=b".to_string()));
+    }
+
+    #[test]
+    fn search_sends_the_query() {
+        let transport = MemoryTransport::new();
+        transport.respond(200, "{\"count\": 0, \"total_count\": 0, \
+                                 \"results\": []}");
+
+        client(&transport).search("users").query("bob").offset(20)
+                          .exec::<User>().unwrap();
+        assert_eq!(transport.requests()[0].url.query,
+                   Some("query=bob&offset=20".to_string()));
+    }
+}
@@ -0,0 +1,162 @@
+use operation::Operation;
+use hyper::status::StatusCode;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Latency buckets, in seconds, of the rendered histogram.
+static BUCKETS: [f64, ..11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+                               2.5, 5.0, 10.0];
+
+// One completed operation, retries included. It is recorded once the response
+// body has been dropped, with the size of the body as read, decoded.
+#[deriving(Clone, Show)]
+pub struct Metric {
+    pub operation: Operation,
+    pub collection: String,
+    pub status: Option<StatusCode>,
+    pub latency: Duration,
+    pub request_bytes: uint,
+    pub response_bytes: uint
+}
+
+impl Metric {
+
+    // "2xx", "4xx", ... or "error" when no response was received
+    pub fn status_class(&self) -> String {
+        match self.status {
+            Some(status) => format!("{}xx", status as u16 / 100),
+            None => "error".to_string()
+        }
+    }
+}
+
+pub trait MetricsSink {
+    fn record(&self, metric: &Metric);
+}
+
+#[deriving(Clone)]
+struct Aggregate {
+    count: u64,
+    latency: f64,
+    buckets: [u64, ..11],
+    request_bytes: u64,
+    response_bytes: u64
+}
+
+type Labels = (Operation, String, String);
+
+// Aggregates metrics in memory, per operation, collection and status class.
+#[deriving(Clone)]
+pub struct InMemoryMetrics {
+    aggregates: Arc<Mutex<BTreeMap<Labels, Aggregate>>>
+}
+
+impl InMemoryMetrics {
+
+    pub fn new() -> InMemoryMetrics {
+        InMemoryMetrics {
+            aggregates: Arc::new(Mutex::new(BTreeMap::new()))
+        }
+    }
+
+    pub fn count(&self, operation: Operation, collection: &str,
+                 status_class: &str) -> u64 {
+        let labels = (operation, collection.to_string(), status_class.to_string());
+        self.aggregates.lock().get(&labels).map_or(0, |agg| agg.count)
+    }
+
+    // renders a snapshot in the Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let aggregates = self.aggregates.lock();
+        let mut out = String::new();
+
+        out.push_str("# TYPE orchestrate_requests_total counter\n");
+        for (labels, agg) in aggregates.iter() {
+            out.push_str(format!("orchestrate_requests_total{{{}}} {}\n",
+                                 render(labels, None), agg.count).as_slice());
+        }
+
+        out.push_str("# TYPE orchestrate_request_duration_seconds histogram\n");
+        for (labels, agg) in aggregates.iter() {
+            for (i, le) in BUCKETS.iter().enumerate() {
+                out.push_str(format!(
+                    "orchestrate_request_duration_seconds_bucket{{{}}} {}\n",
+                    render(labels, Some(le.to_string())), agg.buckets[i]
+                ).as_slice());
+            }
+            out.push_str(format!(
+                "orchestrate_request_duration_seconds_bucket{{{}}} {}\n",
+                render(labels, Some("+Inf".to_string())), agg.count).as_slice());
+            out.push_str(format!(
+                "orchestrate_request_duration_seconds_sum{{{}}} {}\n",
+                render(labels, None), agg.latency).as_slice());
+            out.push_str(format!(
+                "orchestrate_request_duration_seconds_count{{{}}} {}\n",
+                render(labels, None), agg.count).as_slice());
+        }
+
+        out.push_str("# TYPE orchestrate_request_bytes_total counter\n");
+        for (labels, agg) in aggregates.iter() {
+            out.push_str(format!("orchestrate_request_bytes_total{{{}}} {}\n",
+                                 render(labels, None),
+                                 agg.request_bytes).as_slice());
+        }
+
+        out.push_str("# TYPE orchestrate_response_bytes_total counter\n");
+        for (labels, agg) in aggregates.iter() {
+            out.push_str(format!("orchestrate_response_bytes_total{{{}}} {}\n",
+                                 render(labels, None),
+                                 agg.response_bytes).as_slice());
+        }
+
+        out
+    }
+}
+
+impl MetricsSink for InMemoryMetrics {
+    fn record(&self, metric: &Metric) {
+        let labels = (metric.operation.clone(), metric.collection.clone(),
+                      metric.status_class());
+        let seconds = metric.latency.num_microseconds().unwrap_or(0) as f64
+                      / 1_000_000.0;
+
+        let mut aggregates = self.aggregates.lock();
+        if !aggregates.contains_key(&labels) {
+            aggregates.insert(labels.clone(), Aggregate {
+                count: 0,
+                latency: 0.0,
+                buckets: [0, ..11],
+                request_bytes: 0,
+                response_bytes: 0
+            });
+        }
+
+        let agg = aggregates.get_mut(&labels).unwrap();
+        agg.count += 1;
+        agg.latency += seconds;
+        agg.request_bytes += metric.request_bytes as u64;
+        agg.response_bytes += metric.response_bytes as u64;
+        for (i, le) in BUCKETS.iter().enumerate() {
+            if seconds <= *le {
+                agg.buckets[i] += 1;
+            }
+        }
+    }
+}
+
+fn render(labels: &Labels, le: Option<String>) -> String {
+    let &(ref operation, ref collection, ref status) = labels;
+    let mut out = format!("operation=\"{}\",collection=\"{}\",status=\"{}\"",
+                          operation.name(), escape(collection.as_slice()),
+                          status);
+    match le {
+        Some(le) => out.push_str(format!(",le=\"{}\"", le).as_slice()),
+        None => {}
+    }
+    out
+}
+
+fn escape(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
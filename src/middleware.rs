@@ -0,0 +1,129 @@
+use client::RequestBuilder;
+use transport::Response;
+use error::OrchestrateError;
+
+// Runs around every request, in the order it was added: `request` on the way
+// out, `response` on the way back in reverse order.
+pub trait Middleware {
+
+    // may change the request; returning a response answers it without
+    // running the rest of the chain or contacting the server
+    fn request(&self, _req: &mut RequestBuilder) -> Option<Response> {
+        None
+    }
+
+    fn response(&self, _req: &RequestBuilder,
+                res: Result<Response, OrchestrateError>)
+                -> Result<Response, OrchestrateError> {
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Middleware;
+    use Orchestrate;
+    use client::RequestBuilder;
+    use transport::{Response, MemoryTransport};
+    use error::OrchestrateError;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+    use std::io::MemReader;
+    use std::sync::{Arc, Mutex};
+
+    #[deriving(Encodable, Decodable, Show)]
+    struct User {
+        name: String
+    }
+
+    struct Record {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>
+    }
+
+    impl Middleware for Record {
+        fn request(&self, _: &mut RequestBuilder) -> Option<Response> {
+            self.log.lock().push(format!("{} request", self.name));
+            None
+        }
+
+        fn response(&self, _: &RequestBuilder,
+                    res: Result<Response, OrchestrateError>)
+                    -> Result<Response, OrchestrateError> {
+            self.log.lock().push(format!("{} response", self.name));
+            res
+        }
+    }
+
+    struct Answer;
+
+    impl Middleware for Answer {
+        fn request(&self, _: &mut RequestBuilder) -> Option<Response> {
+            Some(Response::new(StatusCode::Ok, Headers::new(),
+                               box MemReader::new(Vec::new())))
+        }
+    }
+
+    struct Rename;
+
+    impl Middleware for Rename {
+        fn request(&self, req: &mut RequestBuilder) -> Option<Response> {
+            req.rename_collection("people");
+            None
+        }
+    }
+
+    fn client(transport: &MemoryTransport) -> Orchestrate {
+        let mut client = Orchestrate::new("key");
+        client.set_transport(transport.clone());
+        client
+    }
+
+    fn record(name: &'static str, log: &Arc<Mutex<Vec<String>>>) -> Record {
+        Record { name: name, log: log.clone() }
+    }
+
+    #[test]
+    fn responses_unwind_in_reverse_order() {
+        let transport = MemoryTransport::new();
+        transport.respond(200, "");
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut client = client(&transport);
+        client.add_middleware(record("first", &log));
+        client.add_middleware(record("second", &log));
+
+        assert!(client.ping().unwrap());
+        assert_eq!(*log.lock(), vec!["first request".to_string(),
+                                     "second request".to_string(),
+                                     "second response".to_string(),
+                                     "first response".to_string()]);
+    }
+
+    #[test]
+    fn a_middleware_can_answer_without_the_server() {
+        let transport = MemoryTransport::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut client = client(&transport);
+        client.add_middleware(record("outer", &log));
+        client.add_middleware(Answer);
+        client.add_middleware(record("inner", &log));
+
+        assert!(client.ping().unwrap());
+        assert!(transport.requests().is_empty());
+        assert_eq!(*log.lock(), vec!["outer request".to_string(),
+                                     "outer response".to_string()]);
+    }
+
+    #[test]
+    fn results_follow_a_renamed_collection() {
+        let transport = MemoryTransport::new();
+        transport.respond(200, "{\"name\": \"bob\"}");
+        let mut client = client(&transport);
+        client.add_middleware(Rename);
+
+        let user = client.get("users", "bob").exec::<User>().unwrap();
+        assert_eq!(user.path.collection.as_slice(), "people");
+        let url = transport.requests()[0].url.clone();
+        assert_eq!(url.serialize_path().unwrap().as_slice(), "/v0/people/bob");
+    }
+}
@@ -0,0 +1,149 @@
+// One piece of a request path. Names given by the caller are validated and
+// percent-encoded, literals are used as they are.
+#[deriving(Clone, Show)]
+pub enum Segment<'a> {
+    Collection(&'a str),
+    Key(&'a str),
+    // of an event or a relation
+    Kind(&'a str),
+    Ref(&'a str),
+    Literal(&'a str)
+}
+
+// Collections and kinds are made of ASCII letters, digits, '-', '_' and '.';
+// keys may be any text without control characters; refs are alphanumeric.
+pub fn validate(segment: &Segment) -> Result<(), String> {
+    let (what, name) = match *segment {
+        Segment::Collection(name) => ("collection", name),
+        Segment::Key(name) => ("key", name),
+        Segment::Kind(name) => ("kind", name),
+        Segment::Ref(name) => ("ref", name),
+        Segment::Literal(_) => return Ok(())
+    };
+
+    if name.is_empty() {
+        return Err(format!("{} name must not be empty", what));
+    }
+
+    // these would be resolved away as relative path segments
+    if name == "." || name == ".." {
+        return Err(format!("invalid {} name {}", what, name));
+    }
+
+    let valid = match *segment {
+        Segment::Collection(_) | Segment::Kind(_) => name.chars().all(|c| {
+            c.is_ascii() && (c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+        }),
+        Segment::Ref(_) => name.chars().all(|c| c.is_ascii() && c.is_alphanumeric()),
+        _ => !name.chars().any(|c| c.is_control())
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("invalid {} name {}", what, name))
+    }
+}
+
+// percent-encodes everything but the unreserved characters of RFC 3986
+pub fn encode(name: &str) -> String {
+    let mut out = String::new();
+    for &byte in name.as_bytes().iter() {
+        match byte as char {
+            'A'...'Z' | 'a'...'z' | '0'...'9' | '-' | '.' | '_' | '~' => {
+                out.push(byte as char)
+            },
+            _ => out.push_str(format!("%{:02X}", byte).as_slice())
+        }
+    }
+    out
+}
+
+pub fn path(segments: &[Segment]) -> Result<String, String> {
+    let mut parts = Vec::new();
+    for segment in segments.iter() {
+        try!(validate(segment));
+        parts.push(match *segment {
+            Segment::Collection(name) | Segment::Key(name) |
+            Segment::Kind(name) | Segment::Ref(name) => encode(name),
+            Segment::Literal(literal) => literal.to_string()
+        });
+    }
+    Ok(parts.connect("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, encode, path};
+    use super::Segment::{Collection, Key, Kind, Ref, Literal};
+    use Orchestrate;
+    use error::ErrorKind;
+    use transport::MemoryTransport;
+
+    #[deriving(Decodable, Show)]
+    struct User {
+        name: String
+    }
+
+    #[test]
+    fn encode_leaves_unreserved_characters() {
+        assert_eq!(encode("a-Z_0.9~").as_slice(), "a-Z_0.9~");
+    }
+
+    #[test]
+    fn encode_escapes_everything_else() {
+        assert_eq!(encode("a b/c?d#").as_slice(), "a%20b%2Fc%3Fd%23");
+        assert_eq!(encode("é").as_slice(), "%C3%A9");
+    }
+
+    #[test]
+    fn validate_collections_and_kinds() {
+        assert!(validate(&Collection("users-2014_v1.0")).is_ok());
+        assert!(validate(&Kind("follows")).is_ok());
+        assert!(validate(&Collection("")).is_err());
+        assert!(validate(&Collection("..")).is_err());
+        assert!(validate(&Collection("a/b")).is_err());
+        assert!(validate(&Kind("a b")).is_err());
+    }
+
+    #[test]
+    fn validate_keys_and_refs() {
+        assert!(validate(&Key("any key/with ?stuff")).is_ok());
+        assert!(validate(&Key("tab\there")).is_err());
+        assert!(validate(&Key(".")).is_err());
+        assert!(validate(&Ref("82eafab14dc84ed3")).is_ok());
+        assert!(validate(&Ref("abc-1")).is_err());
+        assert!(validate(&Literal("")).is_ok());
+    }
+
+    #[test]
+    fn path_encodes_names_but_not_literals() {
+        assert_eq!(path(&[Collection("users"), Key("a/b"), Literal("refs"),
+                          Ref("abc")]),
+                   Ok("users/a%2Fb/refs/abc".to_string()));
+        assert!(path(&[Collection("users"), Key("")]).is_err());
+    }
+
+    #[test]
+    fn keys_are_encoded_in_the_url() {
+        let transport = MemoryTransport::new();
+        transport.respond(200, "{\"name\": \"bob\"}");
+        let mut client = Orchestrate::new("key");
+        client.set_transport(transport.clone());
+
+        client.get("users", "bob smith").exec::<User>().unwrap();
+        assert_eq!(transport.requests()[0].url.to_string().as_slice(),
+                   "https://api.orchestrate.io/v0/users/bob%20smith");
+    }
+
+    #[test]
+    fn invalid_names_are_not_sent() {
+        let transport = MemoryTransport::new();
+        let mut client = Orchestrate::new("key");
+        client.set_transport(transport.clone());
+
+        let err = client.get("no/such", "bob").exec::<User>().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Validation);
+        assert!(transport.requests().is_empty());
+    }
+}
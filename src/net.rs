@@ -0,0 +1,342 @@
+use hyper::net::HttpStream;
+use hyper::net::HttpStream::{Http, Https};
+use hyper::Url;
+use openssl::crypto::hash::SHA256;
+use openssl::nid::Nid;
+use openssl::ssl::{SslContext, SslStream, Sslv23, SslVerifyPeer};
+use openssl::ssl::error::SslError;
+use openssl::x509::X509;
+use serialize::base64::{ToBase64, STANDARD};
+use serialize::hex::ToHex;
+use std::ascii::AsciiExt;
+use std::io::{IoResult, IoError, InvalidInput, OtherIoError, ConnectionFailed};
+use std::io::net::tcp::TcpStream;
+use std::os;
+use transport::Timeouts;
+
+// Show is left out on purpose: the proxy credentials must not end up in logs
+#[deriving(Clone)]
+pub struct Proxy {
+    host: String,
+    port: u16,
+    auth: Option<(String, String)>,
+    no_proxy: Vec<String>
+}
+
+impl Proxy {
+
+    pub fn new(host: &str, port: u16) -> Proxy {
+        Proxy {
+            host: host.to_string(),
+            port: port,
+            auth: None,
+            no_proxy: env_no_proxy()
+        }
+    }
+
+    // reads HTTPS_PROXY, falling back to HTTP_PROXY, in either case; the
+    // proxy url may carry credentials
+    pub fn from_env() -> Option<Proxy> {
+        let value = ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"]
+            .iter()
+            .filter_map(|name| os::getenv(*name))
+            .find(|value| !value.is_empty());
+
+        let url = match value.and_then(|value| Url::parse(value.as_slice()).ok()) {
+            Some(url) => url,
+            None => return None
+        };
+
+        let host = match url.domain() {
+            Some(host) => host.to_string(),
+            None => return None
+        };
+
+        let mut proxy = Proxy::new(host.as_slice(), url.port().unwrap_or(8080));
+        match (url.username(), url.password()) {
+            (Some(user), password) if !user.is_empty() => {
+                proxy = proxy.auth(user, password.unwrap_or(""));
+            },
+            _ => {}
+        }
+        Some(proxy)
+    }
+
+    pub fn auth(mut self, user: &str, password: &str) -> Proxy {
+        self.auth = Some((user.to_string(), password.to_string()));
+        self
+    }
+
+    // hosts reached directly, in addition to those listed in NO_PROXY
+    pub fn no_proxy(mut self, hosts: Vec<&str>) -> Proxy {
+        self.no_proxy.extend(hosts.iter().map(|host| host.to_ascii_lower()));
+        self
+    }
+
+    // "*" matches every host, anything else the host itself and its subdomains
+    pub fn bypass(&self, host: &str) -> bool {
+        let host = host.to_ascii_lower();
+        self.no_proxy.iter().any(|entry| {
+            let entry = entry.as_slice().trim_left_chars('.');
+            entry == "*" || host.as_slice() == entry ||
+                host.as_slice().ends_with(format!(".{}", entry).as_slice())
+        })
+    }
+
+    fn tunnel(&self, tcp: &mut TcpStream, host: &str, port: u16)
+              -> IoResult<()> {
+        let mut request = format!("CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n",
+                                  host, port);
+        match self.auth {
+            Some((ref user, ref password)) => {
+                let credentials = format!("{}:{}", user, password);
+                request.push_str(format!("Proxy-Authorization: Basic {}\r\n",
+                                         credentials.as_bytes()
+                                                    .to_base64(STANDARD))
+                                     .as_slice());
+            },
+            None => {}
+        }
+        request.push_str("\r\n");
+        try!(tcp.write(request.as_bytes()));
+
+        // read the proxy's answer up to the blank line ending its headers,
+        // one byte at a time so nothing of the tunnelled stream is consumed
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(try!(tcp.read_byte()));
+        }
+
+        let head = String::from_utf8_lossy(head.as_slice()).into_string();
+        let status = head.as_slice().split(' ').nth(1);
+        if status != Some("200") {
+            return Err(IoError {
+                kind: ConnectionFailed,
+                desc: "proxy refused to open a tunnel",
+                detail: head.as_slice().lines().next().map(|l| l.to_string())
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn env_no_proxy() -> Vec<String> {
+    os::getenv("NO_PROXY").or_else(|| os::getenv("no_proxy"))
+                          .map(|value| {
+        value.as_slice()
+             .split(',')
+             .map(|host| host.trim().to_ascii_lower())
+             .filter(|host| !host.is_empty())
+             .collect()
+    }).unwrap_or(Vec::new())
+}
+
+#[deriving(Clone, Show)]
+pub struct TlsConfig {
+    ca_files: Vec<Path>,
+    pins: Vec<String>
+}
+
+impl TlsConfig {
+
+    pub fn new() -> TlsConfig {
+        TlsConfig {
+            ca_files: Vec::new(),
+            pins: Vec::new()
+        }
+    }
+
+    // a PEM file of certificates to verify the server against, trusted
+    // besides the system's own
+    pub fn add_root_certificate(mut self, pem: Path) -> TlsConfig {
+        self.ca_files.push(pem);
+        self
+    }
+
+    // accept only servers presenting a certificate with this SHA-256
+    // fingerprint, given in hex with or without colons
+    pub fn pin_sha256(mut self, fingerprint: &str) -> TlsConfig {
+        self.pins.push(fingerprint.chars()
+                                  .filter(|c| *c != ':')
+                                  .collect::<String>()
+                                  .to_ascii_lower());
+        self
+    }
+
+    fn context(&self) -> Result<SslContext, SslError> {
+        let mut context = try!(SslContext::new(Sslv23));
+        if !self.ca_files.is_empty() {
+            context.set_verify(SslVerifyPeer, None);
+            match context.set_default_verify_paths() {
+                Some(err) => return Err(err),
+                None => {}
+            }
+        }
+        for pem in self.ca_files.iter() {
+            match context.set_CA_file(pem) {
+                Some(err) => return Err(err),
+                None => {}
+            }
+        }
+        Ok(context)
+    }
+
+    // a verified chain says nothing about which host the certificate is for
+    fn check_host<S: Stream>(&self, stream: &SslStream<S>, host: &str)
+                             -> IoResult<()> {
+        if self.ca_files.is_empty() {
+            return Ok(());
+        }
+
+        let names = stream.get_peer_certificate()
+                          .map_or(Vec::new(), |cert| host_names(&cert));
+
+        match names.iter().find(|name| matches_host(name.as_slice(), host)) {
+            Some(_) => Ok(()),
+            None => Err(IoError {
+                kind: OtherIoError,
+                desc: "server certificate does not match the host name",
+                detail: Some(host.to_string())
+            })
+        }
+    }
+
+    fn check_pins<S: Stream>(&self, stream: &SslStream<S>) -> IoResult<()> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+
+        let fingerprint = stream.get_peer_certificate()
+                                .and_then(|cert| cert.fingerprint(SHA256))
+                                .map(|digest| digest.as_slice().to_hex());
+
+        match fingerprint {
+            Some(ref fingerprint) if self.pins.contains(fingerprint) => Ok(()),
+            _ => Err(IoError {
+                kind: OtherIoError,
+                desc: "server certificate does not match any pinned fingerprint",
+                detail: fingerprint
+            })
+        }
+    }
+}
+
+#[deriving(Clone)]
+pub struct NetConfig {
+    pub proxy: Option<Proxy>,
+    pub tls: TlsConfig
+}
+
+impl NetConfig {
+    pub fn new() -> NetConfig {
+        NetConfig {
+            proxy: None,
+            tls: TlsConfig::new()
+        }
+    }
+}
+
+pub fn connect(host: &str, port: u16, scheme: &str, timeouts: &Timeouts,
+               config: &NetConfig) -> IoResult<HttpStream> {
+    let proxy = match config.proxy {
+        Some(ref proxy) if !proxy.bypass(host) => Some(proxy),
+        _ => None
+    };
+
+    let addr = match proxy {
+        Some(proxy) => (proxy.host.as_slice(), proxy.port),
+        None => (host, port)
+    };
+
+    let mut tcp = try!(match timeouts.connect {
+        Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+        None => TcpStream::connect(addr)
+    });
+
+    // the proxy tunnel and the TLS handshake are reads too, and must not be
+    // able to hang on a stalled peer
+    tcp.set_read_timeout(timeouts.read.map(|t| t.num_milliseconds() as u64));
+
+    match proxy {
+        Some(proxy) => try!(proxy.tunnel(&mut tcp, host, port)),
+        None => {}
+    }
+
+    match scheme {
+        "http" => Ok(Http(tcp)),
+        "https" => {
+            let context = try!(config.tls.context().map_err(ssl_error));
+            let ssl = try!(SslStream::new(&context, tcp).map_err(ssl_error));
+            try!(config.tls.check_host(&ssl, host));
+            try!(config.tls.check_pins(&ssl));
+            Ok(Https(ssl))
+        },
+        _ => Err(IoError {
+            kind: InvalidInput,
+            desc: "unsupported url scheme",
+            detail: Some(scheme.to_string())
+        })
+    }
+}
+
+// the DNS names in subjectAltName take precedence, the common name only
+// counts for certificates without any
+fn host_names(cert: &X509) -> Vec<String> {
+    let dns: Vec<String> = cert.subject_alt_names().map_or(Vec::new(), |names| {
+        names.iter()
+             .filter_map(|name| name.dnsname().map(|name| name.to_string()))
+             .collect()
+    });
+    if !dns.is_empty() {
+        return dns;
+    }
+
+    cert.subject_name().text_by_nid(Nid::CN)
+                       .map_or(Vec::new(), |name| vec![name.to_string()])
+}
+
+// a leading "*." matches exactly one label
+fn matches_host(name: &str, host: &str) -> bool {
+    let name = name.to_ascii_lower();
+    let host = host.to_ascii_lower();
+    if name.as_slice().starts_with("*.") {
+        match host.as_slice().find('.') {
+            Some(dot) => dot > 0 && host.as_slice().slice_from(dot) ==
+                                    name.as_slice().slice_from(1),
+            None => false
+        }
+    } else {
+        name == host
+    }
+}
+
+fn ssl_error(err: SslError) -> IoError {
+    match err {
+        SslError::StreamError(err) => err,
+        err => IoError {
+            kind: OtherIoError,
+            desc: "ssl error",
+            detail: Some(err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches_host;
+
+    #[test]
+    fn matches_exact_names_ignoring_case() {
+        assert!(matches_host("api.orchestrate.io", "API.orchestrate.io"));
+        assert!(!matches_host("api.orchestrate.io", "orchestrate.io"));
+    }
+
+    #[test]
+    fn wildcards_match_one_label() {
+        assert!(matches_host("*.orchestrate.io", "api.orchestrate.io"));
+        assert!(!matches_host("*.orchestrate.io", "a.api.orchestrate.io"));
+        assert!(!matches_host("*.orchestrate.io", "orchestrate.io"));
+        assert!(!matches_host("*.orchestrate.io", ".orchestrate.io"));
+    }
+}
@@ -0,0 +1,76 @@
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use std::io::stdio;
+use std::time::Duration;
+
+pub static REDACTED: &'static str = "[REDACTED]";
+
+// One attempt at one request, as seen by an `Observer`. Credentials are
+// redacted before an exchange is built.
+#[deriving(Clone, Show)]
+pub struct Exchange {
+    pub method: Method,
+    pub url: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Option<String>,
+    pub status: Option<StatusCode>,
+    pub response_body: Option<String>,
+    pub error: Option<String>,
+    pub latency: Duration,
+    pub attempt: uint
+}
+
+pub trait Observer {
+    fn observe(&self, exchange: &Exchange);
+
+    // request and response bodies are only captured when asked for, since
+    // the response has to be buffered to do so
+    fn wants_bodies(&self) -> bool {
+        false
+    }
+}
+
+// Writes a line per exchange to stderr.
+pub struct StderrObserver {
+    bodies: bool
+}
+
+impl StderrObserver {
+    pub fn new() -> StderrObserver {
+        StderrObserver { bodies: false }
+    }
+
+    pub fn with_bodies() -> StderrObserver {
+        StderrObserver { bodies: true }
+    }
+}
+
+impl Observer for StderrObserver {
+    fn observe(&self, exchange: &Exchange) {
+        let outcome = match (exchange.status, &exchange.error) {
+            (Some(status), _) => status.to_string(),
+            (None, &Some(ref err)) => err.clone(),
+            (None, &None) => "no response".to_string()
+        };
+
+        let mut stderr = stdio::stderr();
+        let _ = writeln!(stderr, "orchestrate: {} {} -> {} ({}ms, attempt {})",
+                         exchange.method, exchange.url, outcome,
+                         exchange.latency.num_milliseconds(), exchange.attempt);
+
+        if self.bodies {
+            match exchange.request_body {
+                Some(ref body) => { let _ = writeln!(stderr, "  > {}", body); },
+                None => {}
+            }
+            match exchange.response_body {
+                Some(ref body) => { let _ = writeln!(stderr, "  < {}", body); },
+                None => {}
+            }
+        }
+    }
+
+    fn wants_bodies(&self) -> bool {
+        self.bodies
+    }
+}
@@ -0,0 +1,76 @@
+use hyper::method::{Method, Get, Head, Put, Post, Delete};
+use std::fmt;
+
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Operation {
+    Ping,
+    DeleteCollection,
+    KvGet,
+    KvCreate,
+    KvUpdate,
+    KvDelete,
+    KvList,
+    Search,
+    EventsList,
+    EventsCreate,
+    EventsDelete,
+    RelationsGet,
+    RelationsPut,
+    RelationsDelete
+}
+
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum OperationClass {
+    Read,
+    Write,
+    Search
+}
+
+impl Operation {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Operation::Ping => "ping",
+            Operation::DeleteCollection => "collection.delete",
+            Operation::KvGet => "kv.get",
+            Operation::KvCreate => "kv.create",
+            Operation::KvUpdate => "kv.update",
+            Operation::KvDelete => "kv.delete",
+            Operation::KvList => "kv.list",
+            Operation::Search => "search",
+            Operation::EventsList => "events.list",
+            Operation::EventsCreate => "events.create",
+            Operation::EventsDelete => "events.delete",
+            Operation::RelationsGet => "relations.get",
+            Operation::RelationsPut => "relations.put",
+            Operation::RelationsDelete => "relations.delete"
+        }
+    }
+
+    pub fn method(&self) -> Method {
+        match *self {
+            Operation::Ping => Head,
+            Operation::KvGet | Operation::KvList | Operation::Search |
+            Operation::EventsList | Operation::RelationsGet => Get,
+            Operation::KvCreate | Operation::EventsCreate => Post,
+            Operation::KvUpdate | Operation::RelationsPut => Put,
+            Operation::DeleteCollection | Operation::KvDelete |
+            Operation::EventsDelete | Operation::RelationsDelete => Delete
+        }
+    }
+
+    pub fn class(&self) -> OperationClass {
+        match *self {
+            Operation::Search => OperationClass::Search,
+            Operation::Ping | Operation::KvGet | Operation::KvList |
+            Operation::EventsList | Operation::RelationsGet => OperationClass::Read,
+            _ => OperationClass::Write
+        }
+    }
+}
+
+impl fmt::Show for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
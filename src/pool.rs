@@ -0,0 +1,142 @@
+use hyper::net::{NetworkConnector, HttpStream};
+use hyper::net::HttpStream::{Http, Https};
+use net::{NetConfig, connect};
+use transport::Timeouts;
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::io::IoResult;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time;
+
+type Key = (String, String, u16);
+
+struct Idle {
+    stream: HttpStream,
+    since: u64
+}
+
+struct PoolState {
+    idle: HashMap<Key, Vec<Idle>>
+}
+
+// Keep-alive connections shared by every request made through one transport,
+// keyed by scheme, host and port.
+#[deriving(Clone)]
+pub struct Pool {
+    max_idle: uint,
+    idle_timeout: Duration,
+    state: Arc<Mutex<PoolState>>
+}
+
+impl Pool {
+
+    pub fn new(max_idle: uint, idle_timeout: Duration) -> Pool {
+        Pool {
+            max_idle: max_idle,
+            idle_timeout: idle_timeout,
+            state: Arc::new(Mutex::new(PoolState { idle: HashMap::new() }))
+        }
+    }
+
+    pub fn connector(&self, timeouts: Timeouts, net: NetConfig) -> PoolConnector {
+        PoolConnector {
+            pool: self.clone(),
+            timeouts: timeouts,
+            net: net,
+            fresh: false,
+            reused: false,
+            checked_out: None
+        }
+    }
+
+    fn checkout(&self, key: &Key) -> Option<HttpStream> {
+        let now = time::precise_time_ns();
+        let timeout = self.idle_timeout.num_milliseconds() as u64 * 1_000_000;
+        let mut state = self.state.lock();
+
+        let idle = match state.idle.get_mut(key) {
+            Some(idle) => idle,
+            None => return None
+        };
+
+        // the most recently used connection is the least likely to be stale
+        loop {
+            match idle.pop() {
+                Some(conn) => if now - conn.since < timeout {
+                    return Some(conn.stream);
+                },
+                None => return None
+            }
+        }
+    }
+
+    fn checkin(&self, key: Key, stream: HttpStream) {
+        let mut state = self.state.lock();
+        if !state.idle.contains_key(&key) {
+            state.idle.insert(key.clone(), Vec::new());
+        }
+
+        let idle = state.idle.get_mut(&key).unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(Idle {
+                stream: stream,
+                since: time::precise_time_ns()
+            });
+        }
+    }
+}
+
+pub struct PoolConnector {
+    pool: Pool,
+    timeouts: Timeouts,
+    net: NetConfig,
+    fresh: bool,
+    reused: bool,
+    checked_out: Option<(Key, HttpStream)>
+}
+
+impl PoolConnector {
+
+    // always open a new connection instead of reusing an idle one
+    pub fn fresh(mut self) -> PoolConnector {
+        self.fresh = true;
+        self
+    }
+
+    // whether the last connection came from the pool
+    pub fn reused(&self) -> bool {
+        self.reused
+    }
+
+    // hands the connection used by the last request back to the pool; only
+    // call this once its response has been read to the end
+    pub fn release(&mut self) {
+        match self.checked_out.take() {
+            Some((key, stream)) => self.pool.checkin(key, stream),
+            None => {}
+        }
+    }
+}
+
+impl NetworkConnector<HttpStream> for PoolConnector {
+    fn connect(&mut self, host: &str, port: u16, scheme: &str)
+               -> IoResult<HttpStream> {
+        let key = (scheme.to_string(), host.to_ascii_lower(), port);
+        let idle = if self.fresh { None } else { self.pool.checkout(&key) };
+        self.reused = idle.is_some();
+        let mut stream = match idle {
+            Some(stream) => stream,
+            None => try!(connect(host, port, scheme, &self.timeouts, &self.net))
+        };
+
+        let read = self.timeouts.read.map(|t| t.num_milliseconds() as u64);
+        match stream {
+            Http(ref mut tcp) => tcp.set_read_timeout(read),
+            Https(ref mut ssl) => ssl.get_inner().set_read_timeout(read)
+        }
+
+        self.checked_out = Some((key, stream.clone()));
+        Ok(stream)
+    }
+}
@@ -0,0 +1,221 @@
+use operation::{Operation, OperationClass};
+use error::OrchestrateError;
+use error::OrchestrateError::{Throttled, Timeout, Validation};
+use std::cmp;
+use std::io::timer;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time;
+
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: u64
+}
+
+impl Bucket {
+    // a bucket that never refills or can't hold a whole token would block
+    // forever
+    fn new(rate: f64, burst: uint) -> Result<Bucket, OrchestrateError> {
+        if !(rate > 0.0) || !rate.is_finite() {
+            return Err(Validation(format!("invalid rate {}, must be positive",
+                                          rate)));
+        }
+        if burst == 0 {
+            return Err(Validation("burst must be at least 1".to_string()));
+        }
+
+        Ok(Bucket {
+            rate: rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: time::precise_time_ns()
+        })
+    }
+
+    fn refill(&mut self, now: u64) {
+        let elapsed = (now - self.updated) as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    // time until a whole token is available, zero if one already is
+    fn wait(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::zero()
+        } else {
+            let secs = (1.0 - self.tokens) / self.rate;
+            Duration::microseconds((secs * 1e6).ceil() as i64)
+        }
+    }
+}
+
+struct Buckets {
+    global: Option<Bucket>,
+    reads: Option<Bucket>,
+    writes: Option<Bucket>,
+    search: Option<Bucket>
+}
+
+// Token buckets for the whole client and per class of operation. Clones
+// share the same budget.
+#[deriving(Clone)]
+pub struct RateLimiter {
+    fail_fast: bool,
+    buckets: Arc<Mutex<Buckets>>
+}
+
+impl RateLimiter {
+
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            fail_fast: false,
+            buckets: Arc::new(Mutex::new(Buckets {
+                global: None,
+                reads: None,
+                writes: None,
+                search: None
+            }))
+        }
+    }
+
+    // `rate` requests per second on average, up to `burst` at once
+    pub fn global(self, rate: f64, burst: uint)
+                  -> Result<RateLimiter, OrchestrateError> {
+        self.buckets.lock().global = Some(try!(Bucket::new(rate, burst)));
+        Ok(self)
+    }
+
+    pub fn reads(self, rate: f64, burst: uint)
+                 -> Result<RateLimiter, OrchestrateError> {
+        self.buckets.lock().reads = Some(try!(Bucket::new(rate, burst)));
+        Ok(self)
+    }
+
+    pub fn writes(self, rate: f64, burst: uint)
+                  -> Result<RateLimiter, OrchestrateError> {
+        self.buckets.lock().writes = Some(try!(Bucket::new(rate, burst)));
+        Ok(self)
+    }
+
+    pub fn search(self, rate: f64, burst: uint)
+                  -> Result<RateLimiter, OrchestrateError> {
+        self.buckets.lock().search = Some(try!(Bucket::new(rate, burst)));
+        Ok(self)
+    }
+
+    // return `Throttled` instead of waiting for the budget to refill
+    pub fn fail_fast(mut self, fail_fast: bool) -> RateLimiter {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    // waits at most `budget` for a token, returning `Timeout` rather than
+    // sleeping past it
+    pub fn acquire(&self, operation: &Operation, budget: Option<Duration>)
+                   -> Result<(), OrchestrateError> {
+        let started = time::precise_time_ns();
+        loop {
+            let wait = self.try_acquire(operation);
+            if wait == Duration::zero() {
+                return Ok(());
+            }
+            if self.fail_fast {
+                return Err(Throttled(wait));
+            }
+            match budget {
+                Some(budget) => {
+                    let spent = (time::precise_time_ns() - started) / 1_000_000;
+                    if wait > budget - Duration::milliseconds(spent as i64) {
+                        return Err(Timeout);
+                    }
+                },
+                None => {}
+            }
+            timer::sleep(wait);
+        }
+    }
+
+    // takes a token from every bucket that applies, or none of them if any
+    // is empty, returning how long to wait in that case
+    fn try_acquire(&self, operation: &Operation) -> Duration {
+        let now = time::precise_time_ns();
+        let mut guard = self.buckets.lock();
+        let buckets = &mut *guard;
+
+        let class = match operation.class() {
+            OperationClass::Read => &mut buckets.reads,
+            OperationClass::Write => &mut buckets.writes,
+            OperationClass::Search => &mut buckets.search
+        };
+
+        let wait = cmp::max(refill(&mut buckets.global, now), refill(class, now));
+        if wait == Duration::zero() {
+            take(&mut buckets.global);
+            take(class);
+        }
+        wait
+    }
+}
+
+fn refill(bucket: &mut Option<Bucket>, now: u64) -> Duration {
+    match *bucket {
+        Some(ref mut bucket) => {
+            bucket.refill(now);
+            bucket.wait()
+        },
+        None => Duration::zero()
+    }
+}
+
+fn take(bucket: &mut Option<Bucket>) {
+    match *bucket {
+        Some(ref mut bucket) => bucket.tokens -= 1.0,
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use error::ErrorKind;
+    use operation::Operation::{KvGet, KvCreate};
+    use std::time::Duration;
+
+    #[test]
+    fn rejects_rates_and_bursts_that_would_block_forever() {
+        assert!(RateLimiter::new().global(0.0, 1).is_err());
+        assert!(RateLimiter::new().global(-1.0, 1).is_err());
+        assert!(RateLimiter::new().reads(1.0, 0).is_err());
+    }
+
+    #[test]
+    fn throttles_once_the_burst_is_spent() {
+        let limiter = RateLimiter::new().global(0.001, 2).unwrap().fail_fast(true);
+        assert!(limiter.acquire(&KvGet, None).is_ok());
+        assert!(limiter.acquire(&KvGet, None).is_ok());
+
+        let err = limiter.acquire(&KvGet, None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Throttled);
+        assert!(err.retry_after().unwrap() > Duration::seconds(100));
+    }
+
+    #[test]
+    fn classes_have_their_own_budget() {
+        let limiter = RateLimiter::new().writes(0.001, 1).unwrap().fail_fast(true);
+        assert!(limiter.acquire(&KvCreate, None).is_ok());
+        assert!(limiter.acquire(&KvCreate, None).is_err());
+        assert!(limiter.acquire(&KvGet, None).is_ok());
+    }
+
+    #[test]
+    fn does_not_wait_past_the_deadline() {
+        let limiter = RateLimiter::new().global(0.001, 1).unwrap();
+        assert!(limiter.acquire(&KvGet, None).is_ok());
+
+        let err = limiter.acquire(&KvGet, Some(Duration::milliseconds(10)))
+                         .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+    }
+}
@@ -0,0 +1,217 @@
+use hyper::HttpError::HttpIoError;
+use error::OrchestrateError;
+use error::OrchestrateError::{HttpError, IoError, Timeout};
+use transport::Response;
+use std::cmp;
+use std::io;
+use std::rand;
+use std::time::Duration;
+use time;
+
+#[deriving(Clone, Show)]
+pub struct RetryPolicy {
+    max_attempts: uint,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    statuses: Vec<u16>,
+    io_errors: Vec<io::IoErrorKind>,
+    honor_retry_after: bool
+}
+
+impl RetryPolicy {
+
+    // three attempts, retrying 429 and 5xx gateway errors as well as dropped
+    // or refused connections
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::milliseconds(100),
+            max_delay: Duration::seconds(10),
+            jitter: true,
+            statuses: vec![429, 500, 502, 503, 504],
+            io_errors: vec![io::ConnectionRefused, io::ConnectionReset,
+                            io::ConnectionAborted, io::BrokenPipe,
+                            io::TimedOut, io::EndOfFile],
+            honor_retry_after: true
+        }
+    }
+
+    // a single attempt, no retries
+    pub fn none() -> RetryPolicy {
+        RetryPolicy { max_attempts: 1, ..RetryPolicy::new() }
+    }
+
+    pub fn max_attempts(mut self, attempts: uint) -> RetryPolicy {
+        self.max_attempts = cmp::max(attempts, 1);
+        self
+    }
+
+    pub fn backoff(mut self, base: Duration, max: Duration) -> RetryPolicy {
+        self.base_delay = base;
+        self.max_delay = max;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn statuses(mut self, statuses: Vec<u16>) -> RetryPolicy {
+        self.statuses = statuses;
+        self
+    }
+
+    pub fn io_errors(mut self, kinds: Vec<io::IoErrorKind>) -> RetryPolicy {
+        self.io_errors = kinds;
+        self
+    }
+
+    pub fn honor_retry_after(mut self, honor: bool) -> RetryPolicy {
+        self.honor_retry_after = honor;
+        self
+    }
+
+    pub fn attempts(&self) -> uint {
+        self.max_attempts
+    }
+
+    pub fn should_retry_status(&self, res: &Response) -> bool {
+        self.statuses.contains(&(res.status as u16))
+    }
+
+    pub fn should_retry_error(&self, err: &OrchestrateError) -> bool {
+        let kind = match *err {
+            HttpError(HttpIoError(ref err)) => err.kind,
+            IoError(ref err) => err.kind,
+            Timeout => io::TimedOut,
+            _ => return false
+        };
+        self.io_errors.contains(&kind)
+    }
+
+    // how long to wait before the attempt following `attempt` (1-based),
+    // preferring the server's Retry-After when there is one
+    pub fn delay(&self, attempt: uint, res: Option<&Response>) -> Duration {
+        if self.honor_retry_after {
+            match res.and_then(retry_after) {
+                Some(delay) => return cmp::min(delay, self.max_delay),
+                None => {}
+            }
+        }
+
+        let exp = cmp::min(attempt - 1, 16);
+        let delay = cmp::min(self.base_delay * (1i32 << exp), self.max_delay);
+
+        if self.jitter {
+            let ms = delay.num_milliseconds() as f64 * rand::random::<f64>();
+            Duration::milliseconds(ms as i64)
+        } else {
+            delay
+        }
+    }
+}
+
+pub fn retry_after(res: &Response) -> Option<Duration> {
+    res.headers.get_raw("Retry-After")
+               .and_then(|values| values.head())
+               .and_then(|value| String::from_utf8(value.clone()).ok())
+               .and_then(|value| parse_retry_after(value.as_slice()))
+}
+
+// either a delay in seconds or an HTTP date, which is waited for from now on
+// and not at all once it has passed
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    match from_str::<i64>(value) {
+        Some(secs) if secs >= 0 => return Some(Duration::seconds(secs)),
+        Some(_) => return None,
+        None => {}
+    }
+
+    time::strptime(value, "%a, %d %b %Y %H:%M:%S GMT").ok().map(|tm| {
+        cmp::max(tm.to_timespec() - time::get_time(), Duration::zero())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryPolicy, parse_retry_after};
+    use Orchestrate;
+    use error::ErrorKind;
+    use transport::MemoryTransport;
+    use std::time::Duration;
+
+    #[deriving(Encodable, Decodable, Show)]
+    struct User {
+        name: String
+    }
+
+    fn client(transport: &MemoryTransport) -> Orchestrate {
+        let mut client = Orchestrate::new("key");
+        client.set_transport(transport.clone());
+        client.set_retry_policy(RetryPolicy::new().jitter(false)
+                                                  .backoff(Duration::milliseconds(1),
+                                                           Duration::milliseconds(1)));
+        client
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let policy = RetryPolicy::new().jitter(false)
+                                       .backoff(Duration::milliseconds(100),
+                                                Duration::seconds(1));
+        assert_eq!(policy.delay(1, None), Duration::milliseconds(100));
+        assert_eq!(policy.delay(2, None), Duration::milliseconds(200));
+        assert_eq!(policy.delay(3, None), Duration::milliseconds(400));
+        assert_eq!(policy.delay(10, None), Duration::seconds(1));
+    }
+
+    #[test]
+    fn retries_reads_on_server_errors() {
+        let transport = MemoryTransport::new();
+        transport.respond(503, "");
+        transport.respond(200, "{\"name\": \"bob\"}");
+
+        client(&transport).get("users", "bob").exec::<User>().unwrap();
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt() {
+        let transport = MemoryTransport::new();
+        for _ in range(0u, 3) {
+            transport.respond(503, "");
+        }
+
+        let err = client(&transport).get("users", "bob").exec::<User>().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ServerError);
+        assert_eq!(transport.requests().len(), 3);
+    }
+
+    #[test]
+    fn does_not_retry_writes_unless_asked() {
+        let transport = MemoryTransport::new();
+        transport.respond(503, "");
+        transport.respond_with_headers(201, vec![("Location",
+                                                  "/v0/users/abc/refs/123")],
+                                       "");
+
+        let user = User { name: "bob".to_string() };
+        assert!(client(&transport).post("users").data(&user).exec().is_err());
+        assert_eq!(transport.requests().len(), 1);
+
+        assert!(client(&transport).post("users").data(&user).retryable()
+                                  .exec().is_ok());
+    }
+
+    #[test]
+    fn parses_retry_after_seconds_and_dates() {
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::seconds(120)));
+        assert_eq!(parse_retry_after("-1"), None);
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+                   Some(Duration::zero()));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
@@ -1,10 +1,15 @@
-use client::Client;
+use client::{Client, RequestBuilder};
+use transport::Response;
 use path::Path;
 use error::OrchestrateError;
-use error::OrchestrateError::RequestError;
-use RepresentsJSON;
+use error::unexpected_status;
+use {RepresentsJSON, OrchestrateFuture};
+use stream::ResultStream;
+use std::time::Duration;
+use std::sync::Arc;
 use serialize::json;
-use hyper::method::Get;
+use operation::Operation::Search;
+use names::Segment::Collection;
 
 #[deriving(Decodable, Encodable, Show)]
 pub struct SearchResults<T> {
@@ -23,45 +28,47 @@ pub struct SearchResult<T> {
     pub value: T
 }
 
-pub struct SearchBuilder<'a> {
-    client: &'a mut Client,
-    url: String
+pub struct SearchBuilder {
+    client: Arc<Client>,
+    request: RequestBuilder
 }
 
-impl<'a> SearchBuilder<'a> {
-    pub fn new<'a>(client: &'a mut Client, collection: &str)
-                   -> SearchBuilder<'a> {
+impl SearchBuilder {
+    pub fn new(client: Arc<Client>, collection: &str)
+                   -> SearchBuilder {
         SearchBuilder {
             client: client,
-            url: collection.to_string()
+            request: RequestBuilder::with_path(Search, collection,
+                                               &[Collection(collection)])
         }
     }
 
-    pub fn limit(mut self, limit: int) -> SearchBuilder<'a> {
-        self.client.query("limit", limit.to_string().as_slice());
+    pub fn limit(mut self, limit: int) -> SearchBuilder {
+        self.request.query("limit", limit.to_string().as_slice());
         self
     }
 
-    pub fn offset(mut self, offset: int) -> SearchBuilder<'a> {
-        self.client.query("offset", offset.to_string().as_slice());
+    pub fn offset(mut self, offset: int) -> SearchBuilder {
+        self.request.query("offset", offset.to_string().as_slice());
         self
     }
 
-    pub fn sort(mut self, prop: &str, sort: &str) -> SearchBuilder<'a> {
-        self.client.query("sort", format!("value.{}:{}", prop, sort).as_slice());
+    pub fn sort(mut self, prop: &str, sort: &str) -> SearchBuilder {
+        self.request.query("sort", format!("value.{}:{}", prop, sort).as_slice());
         self
     }
 
-    pub fn query(mut self, query: &str) -> SearchBuilder<'a> {
-        self.client.query("query", query);
+    pub fn query(mut self, query: &str) -> SearchBuilder {
+        self.request.query("query", query);
         self
     }
 
     pub fn get_next<T: RepresentsJSON>(mut self, results: &SearchResults<T>)
-                    -> SearchBuilder<'a> {
+                    -> SearchBuilder {
         match results.next {
             Some(ref next) => {
-                self.url = next.slice_chars(4, next.len()).to_string();
+                self.request.trailing(self.client.relative_link(next.as_slice())
+                                                 .as_slice());
                 self
             },
             None => self
@@ -69,26 +76,50 @@ impl<'a> SearchBuilder<'a> {
     }
 
     pub fn get_prev<T: RepresentsJSON>(mut self, results: &SearchResults<T>)
-                    -> SearchBuilder<'a> {
+                    -> SearchBuilder {
         match results.prev {
             Some(ref prev) => {
-                self.url = prev.slice_chars(4, prev.len()).to_string();
+                self.request.trailing(self.client.relative_link(prev.as_slice())
+                                                 .as_slice());
                 self
             },
             None => self
         }
     }
 
+    pub fn timeout(mut self, timeout: Duration) -> SearchBuilder {
+        self.request.timeout(timeout);
+        self
+    }
+
     pub fn exec<T: RepresentsJSON>(self)
                 -> Result<SearchResults<T>, OrchestrateError> {
-        let SearchBuilder { client, url } = self;
-        let mut res = try!(client.trailing(url.as_slice()).method(Get).exec());
-        let body = try!(res.read_to_string());
+        let SearchBuilder { client, request } = self;
+        decode_search(try!(client.exec(&request)))
+    }
 
-        if (res.status as i32) != 200 {
-            return Err(RequestError(body));
-        }
+    pub fn exec_async<T: RepresentsJSON + Send>(self)
+                      -> OrchestrateFuture<SearchResults<T>> {
+        let SearchBuilder { client, request } = self;
+        client.exec_async(request, proc(res) decode_search(res))
+    }
 
-        Ok(try!(json::decode::<SearchResults<T>>(body.as_slice())))
+    // yields the results one at a time as the body is read
+    pub fn exec_stream<T: RepresentsJSON>(self)
+                       -> Result<ResultStream<SearchResult<T>>,
+                                 OrchestrateError> {
+        let SearchBuilder { client, request } = self;
+        ResultStream::from_response(try!(client.exec(&request)), 200)
     }
 }
+
+fn decode_search<T: RepresentsJSON>(mut res: Response)
+                                    -> Result<SearchResults<T>, OrchestrateError> {
+    let body = try!(res.read_to_string());
+
+    if (res.status as i32) != 200 {
+        return Err(unexpected_status(200, res.status));
+    }
+
+    Ok(try!(json::decode::<SearchResults<T>>(body.as_slice())))
+}
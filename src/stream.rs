@@ -0,0 +1,294 @@
+use transport::Response;
+use error::{OrchestrateError, unexpected_status};
+use RepresentsJSON;
+use serialize::Decodable;
+use serialize::json;
+use serialize::json::{Json, Parser, JsonEvent, StackElement};
+use serialize::json::JsonEvent::{ObjectStart, ObjectEnd, ArrayStart, ArrayEnd,
+                                 BooleanValue, I64Value, U64Value, F64Value,
+                                 StringValue, NullValue};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{BufferedReader, IoError, EndOfFile};
+use std::rc::Rc;
+
+// Feeds the parser one char at a time straight from the response, keeping
+// any read error aside since the parser would only see the end of input.
+struct Chars {
+    reader: BufferedReader<Response>,
+    error: Rc<RefCell<Option<IoError>>>
+}
+
+impl Iterator<char> for Chars {
+    fn next(&mut self) -> Option<char> {
+        match self.reader.read_char() {
+            Ok(c) => Some(c),
+            Err(ref err) if err.kind == EndOfFile => None,
+            Err(err) => {
+                *self.error.borrow_mut() = Some(err);
+                None
+            }
+        }
+    }
+}
+
+// Decodes the `results` array of a list, search, events or relations
+// response one element at a time while the body is being read, so only one
+// result is held in memory at once. The counts and links are available once
+// the iterator is exhausted, if the server sent them after the results.
+pub struct ResultStream<R> {
+    parser: Parser<Chars>,
+    error: Rc<RefCell<Option<IoError>>>,
+    in_results: bool,
+    done: bool,
+    count: Option<u64>,
+    total_count: Option<u64>,
+    next: Option<String>,
+    prev: Option<String>
+}
+
+impl<R: RepresentsJSON> ResultStream<R> {
+
+    pub fn new(res: Response) -> ResultStream<R> {
+        let error = Rc::new(RefCell::new(None));
+        ResultStream {
+            parser: Parser::new(Chars {
+                reader: BufferedReader::new(res),
+                error: error.clone()
+            }),
+            error: error,
+            in_results: false,
+            done: false,
+            count: None,
+            total_count: None,
+            next: None,
+            prev: None
+        }
+    }
+
+    // streams the response if it has the status a listing answers with
+    pub fn from_response(res: Response, expected: u16)
+                         -> Result<ResultStream<R>, OrchestrateError> {
+        if (res.status as u16) != expected {
+            return Err(unexpected_status(expected, res.status));
+        }
+
+        Ok(ResultStream::new(res))
+    }
+
+    pub fn count(&self) -> Option<u64> {
+        self.count
+    }
+
+    // search results only
+    pub fn total_count(&self) -> Option<u64> {
+        self.total_count
+    }
+
+    pub fn next_link(&self) -> Option<&str> {
+        self.next.as_ref().map(|next| next.as_slice())
+    }
+
+    pub fn prev_link(&self) -> Option<&str> {
+        self.prev.as_ref().map(|prev| prev.as_slice())
+    }
+
+    fn fail(&mut self, err: json::ParserError) -> Option<Result<R, OrchestrateError>> {
+        self.done = true;
+        let err = match self.error.borrow_mut().take() {
+            Some(err) => ::std::error::FromError::from_error(err),
+            None => OrchestrateError::JsonError(json::DecoderError::ParseError(err))
+        };
+        Some(Err(err))
+    }
+
+    // the key the parser is at, for the event it just returned
+    fn key(&self) -> Option<String> {
+        match self.parser.stack().top() {
+            Some(StackElement::Key(key)) => Some(key.to_string()),
+            _ => None
+        }
+    }
+
+    fn value(&mut self, event: JsonEvent) -> Result<Json, json::ParserError> {
+        match event {
+            ObjectStart => {
+                let mut object = BTreeMap::new();
+                loop {
+                    let event = match self.parser.next() {
+                        Some(ObjectEnd) => return Ok(Json::Object(object)),
+                        Some(event) => event,
+                        None => return Err(unexpected_end())
+                    };
+                    let key = self.key().unwrap_or(String::new());
+                    object.insert(key, try!(self.value(event)));
+                }
+            },
+            ArrayStart => {
+                let mut array = Vec::new();
+                loop {
+                    match self.parser.next() {
+                        Some(ArrayEnd) => return Ok(Json::Array(array)),
+                        Some(event) => array.push(try!(self.value(event))),
+                        None => return Err(unexpected_end())
+                    }
+                }
+            },
+            BooleanValue(value) => Ok(Json::Boolean(value)),
+            I64Value(value) => Ok(Json::I64(value)),
+            U64Value(value) => Ok(Json::U64(value)),
+            F64Value(value) => Ok(Json::F64(value)),
+            StringValue(value) => Ok(Json::String(value)),
+            NullValue => Ok(Json::Null),
+            JsonEvent::Error(err) => Err(err),
+            ObjectEnd | ArrayEnd => Err(unexpected_end())
+        }
+    }
+}
+
+fn unexpected_end() -> json::ParserError {
+    json::ParserError::SyntaxError(json::ErrorCode::EOFWhileParsingValue, 0, 0)
+}
+
+impl<R: RepresentsJSON> Iterator<Result<R, OrchestrateError>> for ResultStream<R> {
+    fn next(&mut self) -> Option<Result<R, OrchestrateError>> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            let event = match self.parser.next() {
+                Some(JsonEvent::Error(err)) => return self.fail(err),
+                Some(event) => event,
+                None => {
+                    self.done = true;
+                    return match self.error.borrow_mut().take() {
+                        Some(err) => Some(Err(::std::error::FromError::from_error(err))),
+                        None => None
+                    };
+                }
+            };
+
+            if self.in_results {
+                if event == ArrayEnd {
+                    self.in_results = false;
+                    continue;
+                }
+
+                let json = match self.value(event) {
+                    Ok(json) => json,
+                    Err(err) => return self.fail(err)
+                };
+                let mut decoder = json::Decoder::new(json);
+                return Some(Decodable::decode(&mut decoder).map_err(|err| {
+                    OrchestrateError::JsonError(err)
+                }));
+            }
+
+            // only the top level object is of interest, anything nested
+            // outside of the results is skipped whole
+            if self.parser.stack().len() != 1 {
+                continue;
+            }
+
+            let key = self.key();
+            match (key.as_ref().map(|key| key.as_slice()), event) {
+                (Some("results"), ArrayStart) => self.in_results = true,
+                (Some("count"), U64Value(count)) => self.count = Some(count),
+                (Some("count"), I64Value(count)) => self.count = Some(count as u64),
+                (Some("total_count"), U64Value(count)) => {
+                    self.total_count = Some(count)
+                },
+                (Some("total_count"), I64Value(count)) => {
+                    self.total_count = Some(count as u64)
+                },
+                (Some("next"), StringValue(next)) => self.next = Some(next),
+                (Some("prev"), StringValue(prev)) => self.prev = Some(prev),
+                (_, event @ ObjectStart) | (_, event @ ArrayStart) => {
+                    match self.value(event) {
+                        Ok(_) => {},
+                        Err(err) => return self.fail(err)
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResultStream;
+    use transport::Response;
+    use error::ErrorKind;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+    use hyper::status::StatusCode::Ok as Success;
+    use std::io::MemReader;
+
+    #[deriving(Decodable, Show, PartialEq)]
+    struct Item {
+        name: String
+    }
+
+    fn response(status: StatusCode, body: &str) -> Response {
+        Response::new(status, Headers::new(),
+                      box MemReader::new(body.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn yields_each_result() {
+        let res = response(Success, "{\"count\": 2, \"results\": [\
+                                     {\"name\": \"a\", \"tags\": [1, {}]}, \
+                                     {\"name\": \"b\"}], \
+                                     \"next\": \"/v0/items?offset=2\"}");
+        let mut stream = ResultStream::<Item>::from_response(res, 200).unwrap();
+
+        assert_eq!(stream.next().unwrap().unwrap(), Item { name: "a".to_string() });
+        assert_eq!(stream.next().unwrap().unwrap(), Item { name: "b".to_string() });
+        assert!(stream.next().is_none());
+        assert_eq!(stream.count(), Some(2));
+        assert_eq!(stream.next_link(), Some("/v0/items?offset=2"));
+        assert_eq!(stream.prev_link(), None);
+    }
+
+    #[test]
+    fn skips_nested_values_outside_the_results() {
+        let res = response(Success, "{\"meta\": {\"results\": [1]}, \
+                                     \"results\": [{\"name\": \"a\"}]}");
+        let stream = ResultStream::from_response(res, 200).unwrap();
+        let items: Vec<Item> = stream.map(|item| item.unwrap()).collect();
+        assert_eq!(items, vec![Item { name: "a".to_string() }]);
+    }
+
+    #[test]
+    fn keeps_counts_and_links() {
+        let res = response(Success, "{\"results\": [], \"count\": 0, \
+                                     \"total_count\": 7, \
+                                     \"prev\": \"/v0/items?offset=0\"}");
+        let mut stream = ResultStream::<Item>::from_response(res, 200).unwrap();
+
+        assert!(stream.next().is_none());
+        assert_eq!(stream.count(), Some(0));
+        assert_eq!(stream.total_count(), Some(7));
+        assert_eq!(stream.prev_link(), Some("/v0/items?offset=0"));
+    }
+
+    #[test]
+    fn reports_malformed_bodies() {
+        let res = response(Success, "{\"results\": [{\"name\": ");
+        let mut stream = ResultStream::<Item>::from_response(res, 200).unwrap();
+
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn rejects_unexpected_statuses() {
+        let res = response(StatusCode::NoContent, "");
+        match ResultStream::<Item>::from_response(res, 200) {
+            Err(err) => assert_eq!(err.kind(), ErrorKind::Protocol),
+            Ok(_) => panic!("expected an error")
+        }
+    }
+}
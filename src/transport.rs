@@ -0,0 +1,371 @@
+use hyper::client;
+use hyper::method::{Method, Head};
+use hyper::status::StatusCode;
+use hyper::header::Headers;
+use hyper::header::common::ContentLength;
+use hyper::{Url, HttpError, HttpResult};
+use error::OrchestrateError;
+use observer::REDACTED;
+use std::ascii::AsciiExt;
+use std::fmt;
+use pool::{Pool, PoolConnector};
+use net::{NetConfig, Proxy, TlsConfig};
+use std::io::{IoResult, MemReader, EndOfFile, TimedOut};
+use std::io::{ConnectionReset, ConnectionAborted, BrokenPipe};
+use std::error::FromError;
+use flate2::CompressionLevel;
+use flate2::reader::GzDecoder;
+use flate2::writer::GzEncoder;
+use std::num::FromPrimitive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Show is implemented by hand so the API key never ends up in logs
+#[deriving(Clone)]
+pub struct Request {
+    pub method: Method,
+    pub url: Url,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+    // the body may be sent gzipped; the transport that does so also sets
+    // Content-Encoding, everyone else sees the plain body
+    pub gzip: bool,
+    // safe to send again if the server may already have handled it
+    pub retryable: bool,
+    pub timeouts: Timeouts
+}
+
+#[deriving(Clone, Show)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub read: Option<Duration>,
+    pub total: Option<Duration>
+}
+
+impl Timeouts {
+    pub fn none() -> Timeouts {
+        Timeouts { connect: None, read: None, total: None }
+    }
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+                    .find(|&&(ref n, _)| n.as_slice().eq_ignore_ascii_case(name))
+                    .map(|&(_, ref v)| v.as_slice())
+    }
+}
+
+impl fmt::Show for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let headers: Vec<(&str, &str)> = self.headers.iter().map(|header| {
+            let (ref name, ref value) = *header;
+            if name.as_slice().eq_ignore_ascii_case("Authorization") {
+                (name.as_slice(), REDACTED)
+            } else {
+                (name.as_slice(), value.as_slice())
+            }
+        }).collect();
+        write!(f, "Request {{ method: {}, url: {}, headers: {}, body: {}, \
+                   gzip: {}, retryable: {}, timeouts: {} }}",
+               self.method, self.url, headers, self.body, self.gzip,
+               self.retryable, self.timeouts)
+    }
+}
+
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    body: Box<Reader + Send>
+}
+
+impl Response {
+    pub fn new(status: StatusCode, headers: Headers, body: Box<Reader + Send>)
+               -> Response {
+        Response {
+            status: status,
+            headers: headers,
+            body: body
+        }
+    }
+}
+
+impl Reader for Response {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        self.body.read(buf)
+    }
+}
+
+pub trait Transport {
+    fn send(&self, req: Request) -> Result<Response, OrchestrateError>;
+}
+
+#[deriving(Clone)]
+pub struct HttpTransport {
+    pool: Pool,
+    net: NetConfig
+}
+
+impl HttpTransport {
+    pub fn new() -> HttpTransport {
+        HttpTransport::with_pool(Pool::new(8, Duration::seconds(30)))
+    }
+
+    pub fn with_pool(pool: Pool) -> HttpTransport {
+        HttpTransport {
+            pool: pool,
+            net: NetConfig::new()
+        }
+    }
+
+    pub fn pool(mut self, pool: Pool) -> HttpTransport {
+        self.pool = pool;
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Option<Proxy>) -> HttpTransport {
+        self.net.proxy = proxy;
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> HttpTransport {
+        self.net.tls = tls;
+        self
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&self, req: Request) -> Result<Response, OrchestrateError> {
+        let Request { method, url, mut headers, body, gzip, retryable,
+                      timeouts } = req;
+        let head = method == Head;
+        let body = match body {
+            Some(ref body) if gzip => {
+                headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+                Some(try!(compress(body.as_bytes())))
+            },
+            Some(body) => Some(body.into_bytes()),
+            None => None
+        };
+
+        // a pooled connection the server closed while it sat idle fails
+        // before any of the response arrives; the request may still have
+        // reached the server, so only those safe to repeat get a second try
+        // on a new connection
+        let connector = self.pool.connector(timeouts.clone(), self.net.clone());
+        let (res, mut connector) = match exchange(&method, &url, &headers,
+                                                  &body, connector) {
+            Ok(sent) => sent,
+            Err((true, ref err)) if retryable && stale(err) => {
+                let connector = self.pool.connector(timeouts, self.net.clone())
+                                         .fresh();
+                match exchange(&method, &url, &headers, &body, connector) {
+                    Ok(sent) => sent,
+                    Err((_, err)) => return Err(FromError::from_error(err))
+                }
+            },
+            Err((_, err)) => return Err(FromError::from_error(err))
+        };
+
+        let keep_alive = match res.headers.get_raw("Connection") {
+            Some(values) => !values.iter().any(|v| {
+                v.as_slice().eq_ignore_ascii_case(b"close")
+            }),
+            None => true
+        };
+
+        // nothing left to read, so the connection can go straight back
+        if keep_alive && res.headers.get::<ContentLength>() == Some(&ContentLength(0)) {
+            connector.release();
+        }
+
+        let gzipped = !head &&
+            res.headers.get::<ContentLength>() != Some(&ContentLength(0)) &&
+            res.headers.get_raw("Content-Encoding").map_or(false, |values| {
+                values.iter().any(|v| v.as_slice().eq_ignore_ascii_case(b"gzip"))
+            });
+
+        // callers only ever see the decoded body, whose length isn't known
+        let mut headers = res.headers.clone();
+        if gzipped {
+            headers.remove_raw("Content-Encoding");
+            headers.remove_raw("Content-Length");
+        }
+
+        let status = res.status;
+        let body = box PooledBody {
+            res: res,
+            connector: connector,
+            keep_alive: keep_alive
+        } as Box<Reader + Send>;
+
+        Ok(Response {
+            status: status,
+            headers: headers,
+            body: if gzipped {
+                box try!(GzDecoder::new(body)) as Box<Reader + Send>
+            } else {
+                body
+            }
+        })
+    }
+}
+
+// sends the request and reads the response head, telling on failure whether
+// the connection was a reused one
+fn exchange(method: &Method, url: &Url, headers: &Vec<(String, String)>,
+            body: &Option<Vec<u8>>, mut connector: PoolConnector)
+            -> Result<(client::Response, PoolConnector), (bool, HttpError)> {
+    match start(method, url, headers, body, &mut connector) {
+        Ok(res) => Ok((res, connector)),
+        Err(err) => Err((connector.reused(), err))
+    }
+}
+
+fn start(method: &Method, url: &Url, headers: &Vec<(String, String)>,
+         body: &Option<Vec<u8>>, connector: &mut PoolConnector)
+         -> HttpResult<client::Response> {
+    let mut req = try!(client::Request::with_connector(method.clone(),
+                                                       url.clone(),
+                                                       connector));
+
+    {
+        let mut req_headers = req.headers_mut();
+        for &(ref name, ref value) in headers.iter() {
+            req_headers.set_raw(name.to_string(),
+                                vec![value.as_bytes().to_vec()]);
+        }
+
+        // the body may have shrunk
+        match *body {
+            Some(ref body) => req_headers.set(ContentLength(body.len())),
+            None => {}
+        }
+    }
+
+    let mut stream = try!(req.start());
+    match *body {
+        Some(ref body) => try!(stream.write(body.as_slice())),
+        None => {}
+    }
+    stream.send()
+}
+
+fn stale(err: &HttpError) -> bool {
+    match *err {
+        HttpError::HttpIoError(ref err) => match err.kind {
+            ConnectionReset | ConnectionAborted | BrokenPipe | EndOfFile => true,
+            _ => false
+        },
+        _ => false
+    }
+}
+
+pub fn compress(body: &[u8]) -> IoResult<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), CompressionLevel::Default);
+    try!(encoder.write(body));
+    encoder.finish()
+}
+
+// Returns the connection to the pool once the body has been read to the end.
+struct PooledBody {
+    res: client::Response,
+    connector: PoolConnector,
+    keep_alive: bool
+}
+
+impl Reader for PooledBody {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        match self.res.read(buf) {
+            Err(ref err) if err.kind == EndOfFile && self.keep_alive => {
+                self.connector.release();
+                self.keep_alive = false;
+                Err(err.clone())
+            },
+            Err(ref err) if err.kind == TimedOut => {
+                self.keep_alive = false;
+                Err(err.clone())
+            },
+            other => other
+        }
+    }
+}
+
+struct CannedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String
+}
+
+struct MemoryState {
+    responses: Vec<CannedResponse>,
+    requests: Vec<Request>
+}
+
+// An in-memory transport that answers requests with queued responses, in
+// order, and keeps every request it receives for later inspection.
+#[deriving(Clone)]
+pub struct MemoryTransport {
+    state: Arc<Mutex<MemoryState>>
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport {
+            state: Arc::new(Mutex::new(MemoryState {
+                responses: Vec::new(),
+                requests: Vec::new()
+            }))
+        }
+    }
+
+    pub fn respond(&self, status: u16, body: &str) -> &MemoryTransport {
+        self.respond_with_headers(status, vec![], body)
+    }
+
+    pub fn respond_with_headers(&self, status: u16,
+                                headers: Vec<(&str, &str)>, body: &str)
+                                -> &MemoryTransport {
+        let mut state = self.state.lock();
+        state.responses.push(CannedResponse {
+            status: status,
+            headers: headers.iter()
+                            .map(|&(n, v)| (n.to_string(), v.to_string()))
+                            .collect(),
+            body: body.to_string()
+        });
+        self
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().requests.clone()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&self, req: Request) -> Result<Response, OrchestrateError> {
+        let mut state = self.state.lock();
+        state.requests.push(req);
+
+        if state.responses.is_empty() {
+            return Err(OrchestrateError::NoResponse(
+                "no response queued on the memory transport".to_string()));
+        }
+
+        let canned = state.responses.remove(0).unwrap();
+        let mut headers = Headers::new();
+        for &(ref name, ref value) in canned.headers.iter() {
+            headers.set_raw(name.to_string(), vec![value.as_bytes().to_vec()]);
+        }
+        headers.set(ContentLength(canned.body.len()));
+
+        let status: StatusCode = match FromPrimitive::from_u16(canned.status) {
+            Some(status) => status,
+            None => return Err(OrchestrateError::Validation(
+                format!("invalid status code {}", canned.status)))
+        };
+
+        Ok(Response::new(status, headers,
+                         box MemReader::new(canned.body.into_bytes())))
+    }
+}